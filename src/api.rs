@@ -1,8 +1,21 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+
 use anyhow::{Context, Result};
 use regex::Regex;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 
-use crate::config::{Config, ResponseFormat};
+use crate::cache::Cache;
+use crate::config::{Config, ResponseFormat, Retry};
+use crate::docs::{self, Docs};
+use crate::examples::{Example, Examples};
+use crate::redact::Redactor;
+use crate::tools::Toolbox;
+use crate::usage::{Ledger, Usage};
 
 #[derive(Debug, Serialize)]
 struct ChatRequest {
@@ -10,20 +23,78 @@ struct ChatRequest {
     messages: Vec<Message>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Message {
-    role: String,
-    content: String,
+/// One message of a chat, in the OpenAI chat completions format
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    /// `system`, `user`, `assistant` or `tool`
+    pub role: String,
+    /// Null when the model only asks for tool calls
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub content: String,
+    /// Tools the model wants called before it answers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// The call a `tool` message holds the result of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
-struct ChatResponse {
-    choices: Vec<Choice>,
+impl Message {
+    /// A message with only text
+    pub fn new(role: &str, content: &str) -> Self {
+        Self {
+            role: role.to_string(),
+            content: content.to_string(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct Choice {
-    message: Message,
+/// A call the model asks for, of one of the tools it was offered
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    /// Identifies the call to the `tool` message answering it
+    pub id: String,
+    /// Always `function`
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The function to call
+    pub function: FunctionCall,
+}
+
+/// A function and the arguments to call it with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionCall {
+    /// The function's name
+    pub name: String,
+    /// JSON encoded arguments
+    #[serde(default)]
+    pub arguments: String,
+}
+
+fn null_as_empty<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// A chat completion response, in the OpenAI format
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatResponse {
+    /// The answers generated; only the first is used
+    pub choices: Vec<Choice>,
+    /// Tokens spent, if the endpoint reported them
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+/// One answer in a chat completion response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Choice {
+    /// The answer
+    pub message: Message,
 }
 
 #[derive(Debug, Deserialize)]
@@ -31,98 +102,517 @@ struct CommandResponse {
     command: Vec<String>,
 }
 
-pub struct ApiClient {
+#[derive(Debug, Deserialize)]
+struct PlanResponse {
+    steps: Vec<PlanStep>,
+}
+
+/// One step of a multi-step plan
+#[derive(Debug, Clone, Deserialize)]
+pub struct PlanStep {
+    /// What the step does
+    pub description: String,
+    /// The command for it
+    pub command: String,
+}
+
+/// What a command does and how risky it is, with other ways to do the same
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Assessment {
+    /// What the command does
+    pub explanation: String,
+    /// How much it could change or break
+    pub risk: Risk,
+    /// Other commands for the same task
+    #[serde(default)]
+    pub alternatives: Vec<String>,
+}
+
+/// How much running a command could change or break
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Risk {
+    /// Only reads
+    Low,
+    /// Changes files or state in a way that can be undone
+    Medium,
+    /// Deletes data, changes the system, or can't be undone
+    High,
+}
+
+/// Something produced by the LLM, along with the model that produced it
+#[derive(Debug, Clone)]
+pub struct Generated<T> {
+    /// What was produced
+    pub value: T,
+    /// The model that produced it
+    pub model: String,
+    /// Whether this was answered from the response cache
+    pub cached: bool,
+    /// Tokens spent producing this, if the endpoint reported them
+    pub usage: Option<Usage>,
+}
+
+/// A shell command produced by the LLM
+pub type GeneratedCommand = Generated<String>;
+
+/// Why a request to the endpoint failed
+#[derive(Debug)]
+pub enum ApiError {
+    /// The endpoint is rate limiting us (429); worth retrying later, or moving
+    /// on to a fallback model straight away
+    RateLimited {
+        /// The endpoint's error
+        message: String,
+        /// How long the endpoint asked us to wait
+        retry_after: Option<Duration>,
+    },
+    /// A transient failure (timeout, dropped connection or 5xx) that may
+    /// succeed if the same request is sent again
+    Retryable {
+        /// What went wrong
+        message: String,
+        /// How long the endpoint asked us to wait, if it did
+        retry_after: Option<Duration>,
+    },
+    /// A failure that sending the same request again won't fix
+    /// (bad API key, malformed request, unparseable response)
+    Fatal(String),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::RateLimited { message, .. } => write!(f, "{}", message),
+            ApiError::Retryable { message, .. } => write!(f, "{}", message),
+            ApiError::Fatal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Something worth telling the user about while a request is under way,
+/// handed to the client's notice hook (see `ApiClient::on_notice`)
+#[derive(Debug, Clone)]
+pub enum Notice {
+    /// A request failed in a way that may not happen again, and is sent
+    /// again after `delay`
+    Retrying {
+        /// What went wrong
+        error: String,
+        /// How long until it's sent again
+        delay: Duration,
+        /// Which retry this is, from 1
+        attempt: u32,
+        /// Most retries there will be
+        max_retries: u32,
+    },
+    /// A model failed, so the next one in the chain is asked instead
+    FallingBack {
+        /// The model that failed
+        model: String,
+        /// How it failed
+        error: String,
+        /// The model asked instead
+        next: String,
+    },
+    /// A draft command is being checked against these programs' documentation
+    CheckingDocs(Vec<String>),
+    /// The model called a tool with these arguments
+    ToolCall {
+        /// The tool's name
+        name: String,
+        /// JSON encoded arguments
+        arguments: String,
+    },
+    /// A request body, as it's about to be sent (with `show-outbound` on)
+    Outbound {
+        /// The model it's for
+        model: String,
+        /// The JSON body
+        body: String,
+    },
+    /// Usage couldn't be added to the ledger
+    Ledger(String),
+    /// The model's responses carry no cost, so `monthly-budget` can't count
+    /// them; given once per client
+    NoCost(String),
+}
+
+impl fmt::Display for Notice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Notice::Retrying { error, delay, attempt, max_retries } => write!(
+                f,
+                "{}\nRetrying in {:.1}s ({}/{})...",
+                error,
+                delay.as_secs_f64(),
+                attempt,
+                max_retries
+            ),
+            Notice::FallingBack { model, error, next } => {
+                write!(f, "{} failed: {}\nFalling back to {}...", model, error, next)
+            }
+            Notice::CheckingDocs(programs) => {
+                write!(f, "Checking against documentation for {}...", programs.join(", "))
+            }
+            Notice::ToolCall { name, arguments } => write!(f, "{}({})", name, arguments),
+            Notice::Outbound { model, body } => write!(f, "Request for {}:\n{}", model, body),
+            Notice::Ledger(error) => write!(f, "{}", error),
+            Notice::NoCost(model) => write!(
+                f,
+                "{} doesn't report what requests cost, so they don't count towards monthly-budget",
+                model
+            ),
+        }
+    }
+}
+
+/// Something that answers chat completion requests for a model. `ApiClient`
+/// tries its providers in turn as a fallback chain, and takes care of
+/// retries, caching, redaction and usage around them
+pub trait Provider: Send + Sync {
+    /// The model, as reported in `Generated::model` and the usage ledger
+    fn model(&self) -> &str;
+
+    /// Where requests go, so the same model behind two endpoints isn't
+    /// cached as one
+    fn endpoint(&self) -> &str;
+
+    /// Adjust a request body before it's cached and sent, for what a
+    /// particular endpoint needs
+    fn prepare(&self, _body: &mut serde_json::Value) {}
+
+    /// Send a single chat completion request in the OpenAI format. Failures
+    /// are classified so the client knows whether to retry or fall back
+    fn chat(
+        &self,
+        body: &serde_json::Value,
+    ) -> impl Future<Output = std::result::Result<ChatResponse, ApiError>> + Send;
+}
+
+/// A model behind an OpenAI-compatible chat completions endpoint
+pub struct HttpProvider {
     client: reqwest::Client,
+    model: String,
     endpoint: String,
     api_key: String,
-    model: String,
 }
 
-impl ApiClient {
-    pub fn new(endpoint: String, api_key: String, model: String) -> Self {
+impl HttpProvider {
+    /// A provider asking `model` at `endpoint`, authenticating with `api_key`
+    pub fn new(client: reqwest::Client, model: &str, endpoint: &str, api_key: &str) -> Self {
         Self {
-            client: reqwest::Client::new(),
-            endpoint,
-            api_key,
-            model,
+            client,
+            model: model.to_string(),
+            endpoint: endpoint.to_string(),
+            api_key: api_key.to_string(),
         }
     }
 
-    /// Request a shell command from the LLM
-    pub async fn request_command(
-        &self,
-        system_prompt: &str,
-        user_message: &str,
-        config: &Config,
-    ) -> Result<String> {
-        let url = format!("{}/chat/completions", self.endpoint);
+    /// One provider for each model in the configuration's fallback chain,
+    /// sharing an HTTP client built from its timeout, proxy and TLS settings
+    pub fn from_config(config: &Config) -> Result<Vec<Self>> {
+        let timeout = Duration::try_from_secs_f64(config.timeout)
+            .with_context(|| format!("Invalid timeout: {}", config.timeout))?;
+        let connect_timeout = Duration::try_from_secs_f64(config.connect_timeout)
+            .with_context(|| format!("Invalid connect-timeout: {}", config.connect_timeout))?;
 
-        let messages = vec![
-            Message {
-                role: "system".to_string(),
-                content: system_prompt.to_string(),
-            },
-            Message {
-                role: "user".to_string(),
-                content: user_message.to_string(),
-            },
-        ];
+        let client = build_http_client(config, timeout, connect_timeout)?;
 
-        let request_body = serde_json::json!({
-            "model": self.model,
-            "messages": messages,
-            "response_format": {
-                "type": "json_schema",
-                "name": "command_response",
-                "strict": true,
-                "schema": {
-                    "type": "object",
-                    "properties": {
-                        "command": {
-                            "type": "string",
-                            "description": "The shell command to execute"
-                        }
-                    },
-                    "required": ["command"]
-                }
-            }
-        });
+        Ok(config
+            .model
+            .iter()
+            .map(|model| {
+                Self::new(
+                    client.clone(),
+                    &model.name,
+                    model.endpoint.as_deref().unwrap_or(&config.endpoint),
+                    model.api_key.as_deref().unwrap_or(&config.api_key),
+                )
+            })
+            .collect())
+    }
+}
+
+impl Provider for HttpProvider {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    fn prepare(&self, body: &mut serde_json::Value) {
+        // OpenRouter only reports cost when asked to
+        if self.endpoint.contains("openrouter.ai") {
+            body["usage"] = serde_json::json!({ "include": true });
+        }
+    }
 
+    async fn chat(&self, body: &serde_json::Value) -> std::result::Result<ChatResponse, ApiError> {
+        let url = format!("{}/chat/completions", self.endpoint);
         let response = self
             .client
-            .post(&url)
+            .post(url)
             .header("Authorization", format!("Bearer {}", self.api_key))
             .header("Content-Type", "application/json")
-            .json(&request_body)
+            .json(body)
             .send()
             .await
-            .context("Failed to send request to API")?;
+            .map_err(|e| classify_reqwest_error("Failed to send request to API", e))?;
 
-        if !response.status().is_success() {
-            let status = response.status();
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = parse_retry_after(response.headers());
             let error_text = response
                 .text()
                 .await
                 .unwrap_or_else(|_| "Unknown error".to_string());
-            anyhow::bail!("API request failed with status {}: {}", status, error_text);
+            let message = format!("API request failed with status {}: {}", status, error_text);
+
+            return Err(if status == StatusCode::TOO_MANY_REQUESTS {
+                ApiError::RateLimited {
+                    message,
+                    retry_after,
+                }
+            } else if status.is_server_error() {
+                ApiError::Retryable {
+                    message,
+                    retry_after,
+                }
+            } else {
+                ApiError::Fatal(message)
+            });
         }
 
-        let chat_response: ChatResponse = response
+        response
             .json()
             .await
-            .context("Failed to parse API response")?;
+            .map_err(|e| classify_reqwest_error("Failed to parse API response", e))
+    }
+}
 
-        let raw_response = chat_response
-            .choices
-            .first().map(|choice| choice.message.content.trim().to_string())
-            .context("No response from API")?;
+/// Asks models for commands, plans, explanations and answers, falling back
+/// along a chain of providers
+pub struct ApiClient<P: Provider = HttpProvider> {
+    providers: Vec<P>,
+    retry: Retry,
+    quiet: bool,
+    cache: Option<Cache>,
+    ledger: Ledger,
+    monthly_budget: Option<f64>,
+    /// Whether a response without a cost has been noticed yet
+    warned_no_cost: AtomicBool,
+    /// Usage accumulated over every request made by this client
+    session_usage: Mutex<Usage>,
+    redactor: Redactor,
+    show_outbound: bool,
+    /// Whether requests are sent at all, or only shown
+    send: bool,
+    notify: Box<dyn Fn(&Notice) + Send + Sync>,
+}
+
+impl ApiClient {
+    /// A client for the models in the configuration, over HTTP
+    pub fn new(config: &Config) -> Result<Self> {
+        Self::with_providers(config, HttpProvider::from_config(config)?)
+    }
+}
+
+impl<P: Provider> ApiClient<P> {
+    /// A client for the given providers, tried in order; everything else
+    /// (retries, cache, budget, redaction) comes from the configuration
+    pub fn with_providers(config: &Config, providers: Vec<P>) -> Result<Self> {
+        // API keys are masked too, in case they turn up in piped input
+        let mut keys = vec![config.api_key.as_str()];
+        keys.extend(config.model.iter().filter_map(|model| model.api_key.as_deref()));
+        let redactor = Redactor::new(&config.redact, &keys)?;
 
-        // Clean up the response (remove markdown code blocks)
-        let command = match config.response_format {
-            ResponseFormat::Harmony => cleave_start_parse_json(&raw_response),
-            _ => Ok(clean_command_response(&raw_response)),
+        let cache = if config.cache.enabled {
+            Some(Cache::new(Duration::from_secs(config.cache.ttl))?)
+        } else {
+            None
         };
-        command
+
+        Ok(Self {
+            providers,
+            retry: config.retry.clone(),
+            quiet: config.quiet,
+            cache,
+            ledger: Ledger::open()?,
+            monthly_budget: config.monthly_budget,
+            warned_no_cost: AtomicBool::new(false),
+            session_usage: Mutex::new(Usage::default()),
+            redactor,
+            show_outbound: config.show_outbound,
+            send: true,
+            notify: Box::new(|_| {}),
+        })
+    }
+
+    /// Have notices (retries, fallbacks, tool calls and the like) handed to
+    /// `hook`; without one they're dropped. Apart from outbound requests,
+    /// there are none when the configuration is quiet
+    pub fn on_notice(mut self, hook: impl Fn(&Notice) + Send + Sync + 'static) -> Self {
+        self.notify = Box::new(hook);
+        self
+    }
+
+    /// Show requests (as `Notice::Outbound`) instead of sending them; every
+    /// request then fails once it's been shown
+    pub fn without_sending(mut self) -> Self {
+        self.show_outbound = true;
+        self.send = false;
+        self
+    }
+
+    fn notice(&self, notice: Notice) {
+        if !self.quiet || matches!(notice, Notice::Outbound { .. }) {
+            (self.notify)(&notice);
+        }
+    }
+
+    /// Request a command the way `please` does: with the configured and
+    /// learned examples, and with grounding on, documentation for the programs
+    /// the request names sent along with it, then a second pass checking the
+    /// command's flags against the documentation of the programs it uses
+    pub async fn generate_command(
+        &self,
+        system_prompt: &str,
+        user_request: &str,
+        config: &Config,
+    ) -> Result<GeneratedCommand> {
+        let mut examples = config.prompts.examples.clone();
+        if config.learn.enabled {
+            // the closest last, right before the request itself
+            let similar = Examples::open()?.similar(user_request, config.learn.max_examples)?;
+            examples.extend(similar.into_iter().rev());
+        }
+        if !config.grounding.enabled {
+            return self.request_command(system_prompt, user_request, &examples, config).await;
+        }
+
+        let docs = Docs::new(config)?;
+        let excerpts = docs.lookup_request(user_request);
+        let request = if excerpts.is_empty() {
+            user_request.to_string()
+        } else {
+            format!("{}\n\n{}", user_request, docs::format(&excerpts))
+        };
+        let draft = self.request_command(system_prompt, &request, &examples, config).await?;
+        if !config.grounding.verify {
+            return Ok(draft);
+        }
+
+        let excerpts = docs.lookup(&docs::programs_in_command(&draft.value), &docs::flags_in_command(&draft.value));
+        if excerpts.is_empty() {
+            return Ok(draft);
+        }
+        self.notice(Notice::CheckingDocs(
+            excerpts.iter().map(|excerpt| excerpt.program.clone()).collect(),
+        ));
+        let feedback = format!(
+            "Check every program, flag and argument of that command against the documentation below \
+and fix anything it doesn't support. If it is already correct, give the same command again.\n\n{}",
+            docs::format(&excerpts)
+        );
+        let mut checked = self
+            .refine_command(system_prompt, &request, &draft.value, &feedback, config)
+            .await?;
+        if let Some(usage) = &draft.usage {
+            checked.usage.get_or_insert_default().add(usage);
+        }
+        Ok(checked)
+    }
+
+    /// Request a shell command from the LLM, showing it `examples` of past
+    /// requests and the commands that were settled on for them first
+    pub async fn request_command(
+        &self,
+        system_prompt: &str,
+        user_message: &str,
+        examples: &[Example],
+        config: &Config,
+    ) -> Result<GeneratedCommand> {
+        let mut messages = vec![Message::new("system", system_prompt)];
+        for example in examples {
+            messages.push(Message::new("user", &example.request));
+            messages.push(Message::new("assistant", &example.command));
+        }
+        messages.push(Message::new("user", user_message));
+
+        if config.tools.enabled {
+            return self.request_command_with_tools(messages, config).await;
+        }
+
+        let parse = |content: &str| parse_command(content, config);
+        self.complete(|model| command_request_body(model, &messages, None), parse)
+            .await
+    }
+
+    /// Let the model call read-only tools before it settles on a command,
+    /// feeding their results back for up to `max-iterations` rounds
+    async fn request_command_with_tools(
+        &self,
+        mut messages: Vec<Message>,
+        config: &Config,
+    ) -> Result<GeneratedCommand> {
+        let toolbox = Toolbox::new(&config.tools)?;
+        let definitions = toolbox.definitions();
+        let mut usage: Option<Usage> = None;
+        let mut cached = true;
+
+        for iteration in 0..=config.tools.max_iterations {
+            // on the last round tools are withheld so the model has to answer
+            let tools = (iteration < config.tools.max_iterations && !definitions.is_empty())
+                .then_some(definitions.as_slice());
+            let reply = self
+                .complete_response(
+                    |model| command_request_body(model, &messages, tools),
+                    |response| {
+                        let message = &response.choices.first().context("No response from API")?.message;
+                        if message.tool_calls.is_empty() {
+                            parse_command(message.content.trim(), config)?;
+                        }
+                        Ok(message.clone())
+                    },
+                )
+                .await?;
+
+            cached &= reply.cached;
+            if let Some(reply_usage) = &reply.usage {
+                usage.get_or_insert_default().add(reply_usage);
+            }
+
+            let message = reply.value;
+            if message.tool_calls.is_empty() {
+                return Ok(Generated {
+                    value: parse_command(message.content.trim(), config)?,
+                    model: reply.model,
+                    cached,
+                    usage,
+                });
+            }
+
+            let calls = message.tool_calls.clone();
+            messages.push(message);
+            for call in calls {
+                self.notice(Notice::ToolCall {
+                    name: call.function.name.clone(),
+                    arguments: call.function.arguments.clone(),
+                });
+                let result = toolbox.call(&call.function.name, &call.function.arguments);
+                messages.push(Message {
+                    tool_call_id: Some(call.id),
+                    ..Message::new("tool", &result)
+                });
+            }
+        }
+
+        anyhow::bail!("No command after {} rounds of tool calls", config.tools.max_iterations)
     }
 
     /// Continue a conversation with feedback from the user
@@ -133,69 +623,480 @@ impl ApiClient {
         previous_command: &str,
         feedback: &str,
         config: &Config,
-    ) -> Result<String> {
-        let url = format!("{}/chat/completions", self.endpoint);
+    ) -> Result<GeneratedCommand> {
+        let messages = vec![
+            Message::new("system", system_prompt),
+            Message::new("user", original_request),
+            Message::new("assistant", previous_command),
+            Message::new("user", feedback),
+        ];
+
+        let parse = |content: &str| parse_command(content, config);
+        self.complete(|model| ChatRequest {
+            model: model.to_string(),
+            messages: messages.clone(),
+        }, parse)
+        .await
+    }
 
+    /// Request an ordered list of commands for a task that takes several steps
+    pub async fn request_plan(
+        &self,
+        system_prompt: &str,
+        user_message: &str,
+    ) -> Result<Generated<Vec<PlanStep>>> {
         let messages = vec![
-            Message {
-                role: "system".to_string(),
-                content: system_prompt.to_string(),
-            },
-            Message {
-                role: "user".to_string(),
-                content: original_request.to_string(),
-            },
-            Message {
-                role: "assistant".to_string(),
-                content: previous_command.to_string(),
-            },
-            Message {
-                role: "user".to_string(),
-                content: feedback.to_string(),
-            },
+            Message::new("system", system_prompt),
+            Message::new("user", user_message),
         ];
 
-        let request_body = ChatRequest {
-            model: self.model.clone(),
-            messages,
-        };
+        self.complete(|model| serde_json::json!({
+            "model": model,
+            "messages": messages,
+            "response_format": {
+                "type": "json_schema",
+                "name": "plan_response",
+                "strict": true,
+                "schema": {
+                    "type": "object",
+                    "properties": {
+                        "steps": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "description": {
+                                        "type": "string",
+                                        "description": "What this step does"
+                                    },
+                                    "command": {
+                                        "type": "string",
+                                        "description": "The shell command to execute"
+                                    }
+                                },
+                                "required": ["description", "command"]
+                            }
+                        }
+                    },
+                    "required": ["steps"]
+                }
+            }
+        }), parse_plan)
+        .await
+    }
 
-        let response = self
-            .client
-            .post(&url)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .header("Content-Type", "application/json")
-            .json(&request_body)
-            .send()
+    /// Describe a command generated for a request: what it does, how risky
+    /// it is, and alternatives
+    pub async fn assess_command(
+        &self,
+        system_prompt: &str,
+        request: &str,
+        command: &str,
+    ) -> Result<Generated<Assessment>> {
+        let messages = vec![
+            Message::new("system", system_prompt),
+            Message::new("user", &format!("Task: {}\n\nCommand: {}", request, command)),
+        ];
+
+        self.complete(|model| serde_json::json!({
+            "model": model,
+            "messages": messages,
+            "response_format": {
+                "type": "json_schema",
+                "name": "assessment_response",
+                "strict": true,
+                "schema": {
+                    "type": "object",
+                    "properties": {
+                        "explanation": {
+                            "type": "string",
+                            "description": "What the command does"
+                        },
+                        "risk": {
+                            "type": "string",
+                            "enum": ["low", "medium", "high"]
+                        },
+                        "alternatives": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Other commands for the same task"
+                        }
+                    },
+                    "required": ["explanation", "risk", "alternatives"]
+                }
+            }
+        }), parse_assessment)
+        .await
+    }
+
+    /// Ask the LLM to explain what a shell command does
+    pub async fn explain_command(
+        &self,
+        system_prompt: &str,
+        command: &str,
+    ) -> Result<Generated<String>> {
+        self.request_text(system_prompt, command).await
+    }
+
+    /// Ask the LLM a question, getting a Markdown answer back
+    pub async fn answer_question(
+        &self,
+        system_prompt: &str,
+        question: &str,
+    ) -> Result<Generated<String>> {
+        self.request_text(system_prompt, question).await
+    }
+
+    /// Request a free-form text response
+    async fn request_text(
+        &self,
+        system_prompt: &str,
+        user_message: &str,
+    ) -> Result<Generated<String>> {
+        let messages = vec![
+            Message::new("system", system_prompt),
+            Message::new("user", user_message),
+        ];
+
+        self.complete(|model| ChatRequest {
+            model: model.to_string(),
+            messages: messages.clone(),
+        }, |content| Ok(content.to_string()))
+        .await
+    }
+
+    /// Try each model in the fallback chain in turn until one of them produces
+    /// a response whose content `parse` accepts
+    async fn complete<B: Serialize, T>(
+        &self,
+        request_body: impl Fn(&str) -> B,
+        parse: impl Fn(&str) -> Result<T>,
+    ) -> Result<Generated<T>> {
+        self.complete_response(request_body, |response| response_content(response).and_then(&parse))
             .await
-            .context("Failed to send request to API")?;
+    }
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "Unknown error".to_string());
-            anyhow::bail!("API request failed with status {}: {}", status, error_text);
+    /// Like `complete`, but `parse` gets the whole response rather than just
+    /// its content
+    async fn complete_response<B: Serialize, T>(
+        &self,
+        request_body: impl Fn(&str) -> B,
+        parse: impl Fn(&ChatResponse) -> Result<T>,
+    ) -> Result<Generated<T>> {
+        let requests = self
+            .providers
+            .iter()
+            .map(|provider| {
+                let mut body = serde_json::to_value(request_body(provider.model()))
+                    .context("Failed to serialize request")?;
+                self.redactor.redact_messages(&mut body);
+                provider.prepare(&mut body);
+                Ok((provider, Cache::key(provider.endpoint(), &body)?, body))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // The primary model's cached answer beats a round trip, unless the
+        // point is to see what would be sent. A fallback's answer isn't used
+        // without trying the primary first, as it only stood in for it
+        if let Some(cache) = self.cache.as_ref().filter(|_| self.send)
+            && let Some((provider, key, _)) = requests.first()
+            && let Some(chat_response) = cache.get::<ChatResponse>(key)
+            && let Ok(value) = parse(&chat_response)
+        {
+            return Ok(Generated {
+                value,
+                model: provider.model().to_string(),
+                cached: true,
+                usage: None,
+            });
         }
 
-        let chat_response: ChatResponse = response
-            .json()
-            .await
-            .context("Failed to parse API response")?;
+        if let Some(budget) = self.monthly_budget {
+            self.ledger.check_budget(budget)?;
+        }
+
+        let mut failures = Vec::new();
+
+        for (i, (provider, key, body)) in requests.iter().enumerate() {
+            let next = self.providers.get(i + 1);
+            if self.show_outbound {
+                self.notice(Notice::Outbound {
+                    model: provider.model().to_string(),
+                    body: serde_json::to_string_pretty(body).unwrap_or_default(),
+                });
+            }
+            if !self.send {
+                anyhow::bail!("Not sent, as asked");
+            }
+            let result = match self.send_chat(provider, body, next.is_some()).await {
+                Ok(chat_response) => {
+                    self.record_usage(provider.model(), chat_response.usage.as_ref());
+                    parse(&chat_response).inspect(|_| {
+                        if let Some(cache) = &self.cache {
+                            // a cache that can't be written shouldn't stop the command
+                            let _ = cache.put(key, &chat_response);
+                        }
+                    })
+                    .map(|value| (value, chat_response.usage))
+                }
+                Err(e) => Err(e),
+            };
 
-        let raw_response = chat_response
-            .choices
-            .first().map(|choice| choice.message.content.trim().to_string())
-            .context("No response from API")?;
+            match result {
+                Ok((value, usage)) => {
+                    return Ok(Generated {
+                        value,
+                        model: provider.model().to_string(),
+                        cached: false,
+                        usage,
+                    });
+                }
+                Err(e) => {
+                    if let Some(next) = next {
+                        self.notice(Notice::FallingBack {
+                            model: provider.model().to_string(),
+                            error: format!("{:#}", e),
+                            next: next.model().to_string(),
+                        });
+                    }
+                    failures.push(format!("{}: {:#}", provider.model(), e));
+                }
+            }
+        }
+
+        if failures.len() == 1 {
+            anyhow::bail!(failures.remove(0));
+        }
+        anyhow::bail!("All models failed:\n  {}", failures.join("\n  "))
+    }
+
+    /// Usage accumulated over every request made by this client so far
+    pub fn session_usage(&self) -> Usage {
+        *self.session_usage.lock().unwrap()
+    }
 
-        // Clean up the response (remove markdown code blocks)
-        let command = match config.response_format {
-            ResponseFormat::Harmony => cleave_start_parse_json(&raw_response),
-            _ => Ok(clean_command_response(&raw_response)),
+    /// Add a response's usage to the session total and the persistent ledger
+    fn record_usage(&self, model: &str, usage: Option<&Usage>) {
+        if self.monthly_budget.is_some()
+            && usage.is_none_or(|usage| usage.cost.is_none())
+            && !self.warned_no_cost.swap(true, Ordering::Relaxed)
+        {
+            self.notice(Notice::NoCost(model.to_string()));
+        }
+        let Some(usage) = usage else {
+            return;
         };
-        command
+        self.session_usage.lock().unwrap().add(usage);
+        if let Err(e) = self.ledger.record(model, usage) {
+            self.notice(Notice::Ledger(format!("{:#}", e)));
+        }
     }
+
+    /// Send a chat completion request, retrying transient failures with
+    /// exponential backoff. When `has_fallback` is set, rate limiting is not
+    /// waited out since the next model in the chain can be tried instead
+    async fn send_chat(
+        &self,
+        provider: &P,
+        request_body: &serde_json::Value,
+        has_fallback: bool,
+    ) -> Result<ChatResponse> {
+        let initial_backoff = Duration::try_from_secs_f64(self.retry.initial_backoff)
+            .with_context(|| format!("Invalid initial-backoff: {}", self.retry.initial_backoff))?;
+        let max_backoff = Duration::try_from_secs_f64(self.retry.max_backoff)
+            .with_context(|| format!("Invalid max-backoff: {}", self.retry.max_backoff))?;
+
+        let mut attempt = 0;
+        loop {
+            let error = match provider.chat(request_body).await {
+                Ok(chat_response) => return Ok(chat_response),
+                Err(error) => error,
+            };
+
+            let retry_after = match &error {
+                ApiError::RateLimited { .. } if has_fallback => return Err(error.into()),
+                ApiError::RateLimited { retry_after, .. }
+                | ApiError::Retryable { retry_after, .. } => *retry_after,
+                ApiError::Fatal(_) => return Err(error.into()),
+            };
+
+            if attempt >= self.retry.max_retries {
+                return Err(anyhow::Error::new(error)
+                    .context(format!("Giving up after {} attempts", attempt + 1)));
+            }
+
+            let delay = match retry_after {
+                Some(retry_after) if retry_after > max_backoff => {
+                    return Err(anyhow::Error::new(error).context(format!(
+                        "Endpoint asked to retry after {}s, longer than max-backoff ({}s)",
+                        retry_after.as_secs(),
+                        max_backoff.as_secs_f64()
+                    )));
+                }
+                Some(retry_after) => retry_after,
+                None => backoff(initial_backoff, attempt, max_backoff),
+            };
+
+            attempt += 1;
+            self.notice(Notice::Retrying {
+                error: error.to_string(),
+                delay,
+                attempt,
+                max_retries: self.retry.max_retries,
+            });
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Delay before the given retry: exponential in the attempt number, capped
+/// at `max_backoff`, with jitter so that concurrent clients spread out
+fn backoff(initial: Duration, attempt: u32, max_backoff: Duration) -> Duration {
+    let ceiling = initial
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(max_backoff);
+    ceiling.mul_f64(rand::random_range(0.5..=1.0))
+}
+
+/// Build the HTTP client with the configured timeouts, proxy and TLS settings
+fn build_http_client(config: &Config, timeout: Duration, connect_timeout: Duration) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(timeout)
+        .connect_timeout(connect_timeout);
+
+    if let Some(proxy) = &config.proxy {
+        let proxy = reqwest::Proxy::all(proxy)
+            .with_context(|| format!("Invalid proxy URL: {}", proxy))?
+            .no_proxy(reqwest::NoProxy::from_env());
+        builder = builder.proxy(proxy);
+    }
+
+    for path in &config.ca_certificates {
+        let pem = read_pem(path)?;
+        let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("Invalid CA certificate: {}", path))?;
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    match (&config.client_certificate, &config.client_key) {
+        (Some(certificate), Some(key)) => {
+            let identity = reqwest::Identity::from_pkcs8_pem(&read_pem(certificate)?, &read_pem(key)?)
+                .with_context(|| format!("Invalid client certificate or key: {}, {}", certificate, key))?;
+            builder = builder.identity(identity);
+        }
+        (None, None) => {}
+        _ => anyhow::bail!("client-certificate and client-key must be set together"),
+    }
+
+    if config.insecure {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    builder.build().context("Failed to build HTTP client")
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>> {
+    let expanded = shellexpand::full(path)
+        .with_context(|| format!("Failed to expand path: {}", path))?;
+    std::fs::read(expanded.as_ref()).with_context(|| format!("Failed to read {}", expanded))
+}
+
+/// Timeouts and connection failures are worth retrying; anything else
+/// (invalid URL, undecodable body) will fail the same way next time
+fn classify_reqwest_error(context: &str, error: reqwest::Error) -> ApiError {
+    let message = format!("{}: {}", context, error);
+    if error.is_timeout() || error.is_connect() || error.is_request() {
+        ApiError::Retryable {
+            message,
+            retry_after: None,
+        }
+    } else {
+        ApiError::Fatal(message)
+    }
+}
+
+/// Parse a `Retry-After` header, which is either a number of seconds or an HTTP date
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// The content of the first choice of a chat response
+fn response_content(chat_response: &ChatResponse) -> Result<&str> {
+    chat_response
+        .choices
+        .first()
+        .map(|choice| choice.message.content.trim())
+        .context("No response from API")
+}
+
+/// Body of a request for a single command, optionally offering tools
+fn command_request_body(model: &str, messages: &[Message], tools: Option<&[serde_json::Value]>) -> serde_json::Value {
+    let mut body = serde_json::json!({
+        "model": model,
+        "messages": messages,
+        "response_format": {
+            "type": "json_schema",
+            "name": "command_response",
+            "strict": true,
+            "schema": {
+                "type": "object",
+                "properties": {
+                    "command": {
+                        "type": "string",
+                        "description": "The shell command to execute"
+                    }
+                },
+                "required": ["command"]
+            }
+        }
+    });
+    if let Some(tools) = tools {
+        body["tools"] = serde_json::json!(tools);
+    }
+    body
+}
+
+/// Extract the command from a response, in the configured response format
+pub fn parse_command(raw_response: &str, config: &Config) -> Result<String> {
+    // Clean up the response (remove markdown code blocks)
+    let command = match config.response_format {
+        ResponseFormat::Harmony => cleave_start_parse_json(raw_response)?,
+        _ => clean_command_response(raw_response),
+    };
+
+    if command.is_empty() {
+        anyhow::bail!("Empty command in API response");
+    }
+    Ok(command)
+}
+
+/// Extract the steps of a plan from a response, which may be wrapped in a code block
+fn parse_plan(raw_response: &str) -> Result<Vec<PlanStep>> {
+    let start = raw_response.find('{').context("No JSON object in plan response")?;
+    let end = raw_response.rfind('}').context("No JSON object in plan response")?;
+    let json_str = &raw_response[start..=end];
+    let plan: PlanResponse = serde_json::from_str(json_str)
+        .with_context(|| format!("Failed to parse JSON: {}", json_str))?;
+
+    if plan.steps.is_empty() || plan.steps.iter().any(|step| step.command.trim().is_empty()) {
+        anyhow::bail!("Plan has no steps, or a step without a command");
+    }
+    Ok(plan.steps)
+}
+
+/// Extract an assessment from a response, which may be wrapped in a code block
+fn parse_assessment(raw_response: &str) -> Result<Assessment> {
+    let start = raw_response.find('{').context("No JSON object in assessment response")?;
+    let end = raw_response.rfind('}').context("No JSON object in assessment response")?;
+    let json_str = &raw_response[start..=end];
+    serde_json::from_str(json_str).with_context(|| format!("Failed to parse JSON: {}", json_str))
 }
 
 fn cleave_start_parse_json(response: &str) -> Result<String> {
@@ -210,8 +1111,8 @@ fn cleave_start_parse_json(response: &str) -> Result<String> {
 
     let json_str = json_str.as_str();
     let command_response: CommandResponse = serde_json::from_str(json_str)
-        .expect(format!("Failed to parse JSON: {}", json_str).as_str());
-    return Ok(command_response.command.join(" "));
+        .with_context(|| format!("Failed to parse JSON: {}", json_str))?;
+    Ok(command_response.command.join(" "))
 }
 
 /// Clean up the command response by removing markdown code blocks and extra text
@@ -248,3 +1149,272 @@ fn clean_command_response(response: &str) -> String {
         lines.first().map(|l| l.trim().to_string()).unwrap_or_else(|| response.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use reqwest::header::HeaderValue;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const ANSWER: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n\
+{\"choices\":[{\"message\":{\"role\":\"assistant\",\"content\":\"ls\"}}]}";
+
+    /// Answers the nth request with the nth response (the last one over and
+    /// over once they run out), or never answers it if that response is
+    /// empty. Returns the endpoint and a count of requests received
+    async fn serve(responses: Vec<String>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let received = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let n = received.fetch_add(1, Ordering::SeqCst);
+                let response = responses[n.min(responses.len() - 1)].clone();
+                tokio::spawn(async move {
+                    read_request(&mut stream).await;
+                    if response.is_empty() {
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                        return;
+                    }
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        (endpoint, requests)
+    }
+
+    /// Read the headers of a request and as much body as they announce
+    async fn read_request(stream: &mut tokio::net::TcpStream) {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let Ok(n) = stream.read(&mut buf).await else { return };
+            if n == 0 {
+                return;
+            }
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request);
+            let Some(end) = text.find("\r\n\r\n") else { continue };
+            let length = text[..end]
+                .lines()
+                .find_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|n| n.trim().parse().unwrap_or(0)))
+                .unwrap_or(0);
+            if request.len() >= end + 4 + length {
+                return;
+            }
+        }
+    }
+
+    fn status(code: &str, headers: &str) -> String {
+        format!("HTTP/1.1 {}\r\n{}Content-Length: 5\r\n\r\nnope\n", code, headers)
+    }
+
+    fn client(max_retries: u32) -> ApiClient {
+        let mut config: Config = json5::from_str("{ quiet: true, cache: { enabled: false } }").unwrap();
+        config.retry = Retry {
+            max_retries,
+            initial_backoff: 0.01,
+            max_backoff: 5.0,
+        };
+        ApiClient::with_providers(&config, Vec::new()).unwrap()
+    }
+
+    fn provider(endpoint: &str) -> HttpProvider {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_millis(300))
+            .build()
+            .unwrap();
+        HttpProvider::new(http, "model", endpoint, "key")
+    }
+
+    /// A provider that answers every request with the same reply, or fails
+    /// with a fatal error if it has none
+    struct Fake {
+        model: &'static str,
+        reply: Option<&'static str>,
+        requests: Arc<AtomicUsize>,
+    }
+
+    impl Provider for Fake {
+        fn model(&self) -> &str {
+            self.model
+        }
+
+        fn endpoint(&self) -> &str {
+            "fake"
+        }
+
+        async fn chat(&self, _body: &serde_json::Value) -> std::result::Result<ChatResponse, ApiError> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            let content = self.reply.ok_or_else(|| ApiError::Fatal(format!("{} is down", self.model)))?;
+            Ok(ChatResponse {
+                choices: vec![Choice { message: Message::new("assistant", content) }],
+                usage: None,
+            })
+        }
+    }
+
+    const PLAN: &str = r#"{"steps":[{"description":"list","command":"ls"}]}"#;
+
+    /// A client over fakes with these replies, and a count of the requests
+    /// each got
+    fn fakes(replies: &[(&'static str, Option<&'static str>)]) -> (ApiClient<Fake>, Vec<Arc<AtomicUsize>>) {
+        let config: Config = json5::from_str("{ quiet: true, cache: { enabled: false } }").unwrap();
+        let counts: Vec<_> = replies.iter().map(|_| Arc::new(AtomicUsize::new(0))).collect();
+        let providers = replies
+            .iter()
+            .zip(&counts)
+            .map(|(&(model, reply), requests)| Fake { model, reply, requests: requests.clone() })
+            .collect();
+        (ApiClient::with_providers(&config, providers).unwrap(), counts)
+    }
+
+    fn requests(counts: &[Arc<AtomicUsize>]) -> Vec<usize> {
+        counts.iter().map(|count| count.load(Ordering::SeqCst)).collect()
+    }
+
+    async fn send(client: &ApiClient, endpoint: &str) -> Result<ChatResponse> {
+        client
+            .send_chat(&provider(endpoint), &serde_json::json!({ "model": "model" }), false)
+            .await
+    }
+
+    #[test]
+    fn retry_after_in_seconds() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("7"));
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn retry_after_as_a_date() {
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(30));
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(&date).unwrap());
+        let delay = parse_retry_after(&headers).unwrap();
+        assert!(delay > Duration::from_secs(27) && delay <= Duration::from_secs(30), "{:?}", delay);
+
+        // a date that has passed means now
+        let date = httpdate::fmt_http_date(SystemTime::now() - Duration::from_secs(30));
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(&date).unwrap());
+        assert_eq!(parse_retry_after(&headers), Some(Duration::ZERO));
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("soon"));
+        assert_eq!(parse_retry_after(&headers), None);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_waits_for_retry_after() {
+        let (endpoint, requests) = serve(vec![
+            status("429 Too Many Requests", "Retry-After: 1\r\n"),
+            ANSWER.to_string(),
+        ])
+        .await;
+        let started = std::time::Instant::now();
+        let response = send(&client(3), &endpoint).await.unwrap();
+        assert_eq!(response.choices[0].message.content, "ls");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn rate_limit_with_retry_after_date() {
+        let date = httpdate::fmt_http_date(SystemTime::now() - Duration::from_secs(5));
+        let (endpoint, requests) = serve(vec![
+            status("429 Too Many Requests", &format!("Retry-After: {}\r\n", date)),
+            ANSWER.to_string(),
+        ])
+        .await;
+        send(&client(3), &endpoint).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn retry_after_longer_than_max_backoff_gives_up() {
+        let (endpoint, requests) =
+            serve(vec![status("429 Too Many Requests", "Retry-After: 3600\r\n")]).await;
+        let error = send(&client(3), &endpoint).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("longer than max-backoff"), "{:#}", error);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn server_error_is_retried() {
+        let (endpoint, requests) = serve(vec![
+            status("502 Bad Gateway", ""),
+            status("503 Service Unavailable", ""),
+            ANSWER.to_string(),
+        ])
+        .await;
+        send(&client(3), &endpoint).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn client_error_is_not_retried() {
+        let (endpoint, requests) = serve(vec![status("401 Unauthorized", ""), ANSWER.to_string()]).await;
+        let error = send(&client(3), &endpoint).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<ApiError>(), Some(ApiError::Fatal(_))), "{:#}", error);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn timeout_is_retried() {
+        let (endpoint, requests) = serve(vec![String::new(), ANSWER.to_string()]).await;
+        send(&client(3), &endpoint).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let (endpoint, requests) = serve(vec![status("500 Internal Server Error", "")]).await;
+        let error = send(&client(2), &endpoint).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("Giving up after 3 attempts"), "{:#}", error);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn invalid_max_backoff_is_an_error() {
+        let mut client = client(3);
+        client.retry.max_backoff = f64::INFINITY;
+        let error = send(&client, "http://127.0.0.1:1").await.unwrap_err();
+        assert!(error.to_string().contains("Invalid max-backoff"), "{:#}", error);
+    }
+
+    #[tokio::test]
+    async fn falls_back_after_an_error() {
+        let (client, counts) = fakes(&[("primary", None), ("fallback", Some(PLAN))]);
+        let plan = client.request_plan("system", "list files").await.unwrap();
+        assert_eq!(plan.model, "fallback");
+        assert_eq!(plan.value[0].command, "ls");
+        assert_eq!(requests(&counts), [1, 1]);
+    }
+
+    #[tokio::test]
+    async fn falls_back_after_an_unparseable_reply() {
+        let (client, counts) = fakes(&[("primary", Some("sure, here you go")), ("fallback", Some(PLAN))]);
+        let plan = client.request_plan("system", "list files").await.unwrap();
+        assert_eq!(plan.model, "fallback");
+        assert_eq!(requests(&counts), [1, 1]);
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_first_success() {
+        let (client, counts) = fakes(&[("down", None), ("primary", Some(PLAN)), ("fallback", Some(PLAN))]);
+        let plan = client.request_plan("system", "list files").await.unwrap();
+        assert_eq!(plan.model, "primary");
+        assert_eq!(requests(&counts), [1, 1, 0]);
+
+        let (client, _) = fakes(&[("primary", None), ("fallback", Some("not a plan"))]);
+        let error = client.request_plan("system", "list files").await.unwrap_err();
+        assert!(error.to_string().starts_with("All models failed:"), "{:#}", error);
+    }
+}
@@ -0,0 +1,332 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::cache::hex_digest;
+use crate::config;
+use crate::exec::Execution;
+
+/// How much of the log is read at a time, from the end, to find the previous
+/// record's hash
+const TAIL_BYTES: u64 = 64 * 1024;
+
+/// One line of the audit log
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: DateTime<Utc>,
+    user: String,
+    uid: Option<u32>,
+    host: String,
+    cwd: PathBuf,
+    /// The host the command was run on over ssh, if it wasn't run here
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote: Option<&'a str>,
+    request: &'a str,
+    model: &'a str,
+    generated: &'a str,
+    /// The command as run, when the user changed it from the generated one
+    edited: Option<&'a str>,
+    /// Exit code; `None` when the command was killed by a signal, or never ran
+    status: Option<i32>,
+    duration_ms: u128,
+    /// Why the command couldn't be run, when it couldn't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    /// Hash of the record before this one, when hash chaining is on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prev: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+}
+
+/// The result of checking an audit log's hash chain
+pub struct Verification {
+    /// Records in the log
+    pub records: usize,
+    /// Records whose hash chains to the one before
+    pub chained: usize,
+    /// The first broken link, if any
+    pub problem: Option<String>,
+}
+
+/// An append-only JSONL log of every command run, for keeping track of what
+/// `please` did on shared machines. It's opened before a command runs so an
+/// unwritable log stops the command rather than letting it go unrecorded
+pub struct AuditLog {
+    path: PathBuf,
+    file: File,
+    hash_chain: bool,
+}
+
+impl AuditLog {
+    /// Open the configured log for appending, creating it if need be
+    pub fn open(audit: &config::Audit) -> Result<Self> {
+        let path = path(audit)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create audit log directory: {}", parent.display()))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open audit log: {}", path.display()))?;
+        Ok(Self {
+            path,
+            file,
+            hash_chain: audit.hash_chain,
+        })
+    }
+
+    /// Append a record of a command that was run, and how it went
+    pub fn record(
+        &mut self,
+        execution: &Execution,
+        remote: Option<&str>,
+        status: &ExitStatus,
+        duration: Duration,
+    ) -> Result<()> {
+        self.append(execution, remote, status.code(), duration, None)
+    }
+
+    /// Append a record of a command that couldn't be run, e.g. because its
+    /// shell or ssh couldn't be started
+    pub fn record_failure(&mut self, execution: &Execution, remote: Option<&str>, error: &str) -> Result<()> {
+        self.append(execution, remote, None, Duration::ZERO, Some(error))
+    }
+
+    fn append(
+        &mut self,
+        execution: &Execution,
+        remote: Option<&str>,
+        status: Option<i32>,
+        duration: Duration,
+        error: Option<&str>,
+    ) -> Result<()> {
+        let uid = fs::metadata("/proc/self").map(|metadata| metadata.uid()).ok();
+        let mut record = Record {
+            timestamp: Utc::now(),
+            user: user(uid),
+            uid,
+            host: host(),
+            cwd: std::env::current_dir().unwrap_or_default(),
+            remote,
+            request: execution.request,
+            model: execution.model,
+            generated: execution.generated,
+            edited: (execution.command != execution.generated).then_some(execution.command),
+            status,
+            duration_ms: duration.as_millis(),
+            error,
+            prev: None,
+            hash: None,
+        };
+
+        // other runs of `please` may be writing too; hold the lock from
+        // reading the last hash until this record is written after it
+        self.file
+            .lock()
+            .with_context(|| format!("Failed to lock audit log: {}", self.path.display()))?;
+        let written = self.write(&mut record);
+        let _ = self.file.unlock();
+        written.with_context(|| format!("Failed to write audit log: {}", self.path.display()))
+    }
+
+    fn write(&mut self, record: &mut Record) -> Result<()> {
+        if self.hash_chain {
+            record.prev = self.last_hash()?;
+            record.hash = Some(digest(serde_json::to_value(&*record)?));
+        }
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// The hash of the last record in the log, if it has one
+    fn last_hash(&mut self) -> Result<Option<String>> {
+        // read back from the end a chunk at a time until the start of the
+        // last line that isn't blank, however long it is
+        let mut end = self.file.metadata()?.len();
+        let mut line = Vec::new();
+        while end > 0 {
+            let start = end.saturating_sub(TAIL_BYTES);
+            let mut chunk = vec![0; (end - start) as usize];
+            self.file.seek(SeekFrom::Start(start))?;
+            self.file.read_exact(&mut chunk)?;
+            chunk.append(&mut line);
+            line = chunk;
+            line.truncate(line.trim_ascii_end().len());
+            if let Some(newline) = line.iter().rposition(|&byte| byte == b'\n') {
+                line.drain(..=newline);
+                break;
+            }
+            end = start;
+        }
+        Ok(serde_json::from_slice::<Value>(&line)
+            .ok()
+            .and_then(|record| record.get("hash")?.as_str().map(str::to_string)))
+    }
+}
+
+/// Check every hashed record in the log against its contents and the record
+/// before it. Records from before hash chaining was turned on are counted
+/// but can't be checked
+pub fn verify(audit: &config::Audit) -> Result<Verification> {
+    let path = path(audit)?;
+    let content = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read audit log: {}", path.display()))?;
+
+    let mut verification = Verification {
+        records: 0,
+        chained: 0,
+        problem: None,
+    };
+    let mut previous: Option<String> = None;
+    for (i, line) in content.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        verification.records += 1;
+        let problem = |message: &str| Some(format!("line {}: {}", i + 1, message));
+        let Ok(mut record) = serde_json::from_str::<Value>(line) else {
+            verification.problem = problem("not a valid record");
+            break;
+        };
+
+        let hash = record
+            .as_object_mut()
+            .and_then(|fields| fields.remove("hash"))
+            .and_then(|hash| hash.as_str().map(str::to_string));
+        let Some(hash) = hash else {
+            if verification.chained > 0 {
+                verification.problem = problem("record has no hash");
+                break;
+            }
+            previous = None;
+            continue;
+        };
+
+        let prev = record.get("prev").and_then(Value::as_str).map(str::to_string);
+        if prev != previous {
+            verification.problem = problem("previous record's hash doesn't match; a record was removed or changed");
+            break;
+        }
+        if digest(record) != hash {
+            verification.problem = problem("hash doesn't match the record; it was changed");
+            break;
+        }
+        verification.chained += 1;
+        previous = Some(hash);
+    }
+    Ok(verification)
+}
+
+fn path(audit: &config::Audit) -> Result<PathBuf> {
+    match &audit.path {
+        Some(path) => {
+            let expanded = shellexpand::full(path)
+                .with_context(|| format!("Failed to expand path: {}", path))?;
+            Ok(PathBuf::from(expanded.as_ref()))
+        }
+        None => {
+            let xdg_dirs = xdg::BaseDirectories::with_prefix("please")
+                .context("Failed to initialize XDG directories")?;
+            Ok(xdg_dirs.get_data_home().join("audit.jsonl"))
+        }
+    }
+}
+
+/// Hash of a record without its own hash; `Value` keeps its keys sorted, so
+/// this comes out the same when the record is read back in to be verified
+fn digest(record: Value) -> String {
+    hex_digest(record.to_string().as_bytes())
+}
+
+/// The name of the user running `please`, from the password database rather
+/// than the environment where possible
+fn user(uid: Option<u32>) -> String {
+    let from_passwd = uid.and_then(|uid| {
+        let passwd = fs::read_to_string("/etc/passwd").ok()?;
+        passwd.lines().find_map(|line| {
+            let fields = line.split(':').collect::<Vec<_>>();
+            (fields.len() > 2 && fields[2] == uid.to_string()).then(|| fields[0].to_string())
+        })
+    });
+    from_passwd
+        .or_else(|| std::env::var("USER").ok())
+        .or_else(|| std::env::var("LOGNAME").ok())
+        .unwrap_or_default()
+}
+
+fn host() -> String {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    fn audit(name: &str) -> config::Audit {
+        let path = std::env::temp_dir().join(format!("please-audit-{}-{}.jsonl", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        config::Audit {
+            enabled: true,
+            path: Some(path.display().to_string()),
+            hash_chain: true,
+        }
+    }
+
+    fn execution(request: &str) -> Execution<'_> {
+        Execution {
+            request,
+            model: "model",
+            generated: "true",
+            command: "true",
+        }
+    }
+
+    #[test]
+    fn chain_survives_records_longer_than_the_tail() {
+        let audit = audit("long");
+        let mut log = AuditLog::open(&audit).unwrap();
+        let long = "x".repeat(3 * TAIL_BYTES as usize);
+        for request in ["before", &long, "after"] {
+            log.record(&execution(request), None, &ExitStatus::from_raw(0), Duration::ZERO).unwrap();
+        }
+
+        let verification = verify(&audit).unwrap();
+        assert_eq!(verification.problem, None);
+        assert_eq!((verification.records, verification.chained), (3, 3));
+    }
+
+    #[test]
+    fn failures_are_chained_too() {
+        let audit = audit("failure");
+        let mut log = AuditLog::open(&audit).unwrap();
+        log.record_failure(&execution("one"), Some("web1"), "Failed to run ssh").unwrap();
+        log.record(&execution("two"), None, &ExitStatus::from_raw(0), Duration::ZERO).unwrap();
+
+        let verification = verify(&audit).unwrap();
+        assert_eq!(verification.problem, None);
+        assert_eq!(verification.chained, 2);
+        let content = fs::read_to_string(audit.path.unwrap()).unwrap();
+        let first = serde_json::from_str::<Value>(content.lines().next().unwrap()).unwrap();
+        assert_eq!(first["error"], "Failed to run ssh");
+        assert_eq!(first["status"], Value::Null);
+    }
+}
@@ -0,0 +1,192 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// On-disk cache of API responses under `$XDG_CACHE_HOME/please/responses`,
+/// keyed by a hash of the request that produced them; other kinds of entries
+/// live in their own directory next to it
+pub struct Cache {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry<T> {
+    /// Seconds since the unix epoch
+    created: u64,
+    value: T,
+}
+
+impl Cache {
+    /// The response cache, in `$XDG_CACHE_HOME/please/responses`
+    pub fn new(ttl: Duration) -> Result<Self> {
+        Self::named("responses", ttl)
+    }
+
+    /// A cache in `$XDG_CACHE_HOME/please/<name>`
+    pub fn named(name: &str, ttl: Duration) -> Result<Self> {
+        Ok(Self {
+            dir: cache_dir()?.join(name),
+            ttl,
+        })
+    }
+
+    /// Derive a cache key from a request body and where it's sent; the body
+    /// holds the model, the system prompt and the whole message history, so
+    /// identical requests to the same endpoint hash the same
+    pub fn key(endpoint: &str, request: &impl Serialize) -> Result<String> {
+        let mut bytes = serde_json::to_vec(request).context("Failed to serialize request")?;
+        bytes.extend_from_slice(b"\0");
+        bytes.extend_from_slice(endpoint.as_bytes());
+        Ok(hex_digest(&bytes))
+    }
+
+    /// Look up a key, ignoring (and removing) entries older than the TTL
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let path = self.dir.join(format!("{}.json", key));
+        let content = fs::read_to_string(&path).ok()?;
+        let entry: Entry<T> = match serde_json::from_str(&content) {
+            Ok(entry) => entry,
+            Err(_) => {
+                let _ = fs::remove_file(&path);
+                return None;
+            }
+        };
+
+        let age = now().saturating_sub(entry.created);
+        if age > self.ttl.as_secs() {
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+        Some(entry.value)
+    }
+
+    /// Store `value` under `key`, replacing any entry there
+    pub fn put<T: Serialize>(&self, key: &str, value: &T) -> Result<()> {
+        fs::create_dir_all(&self.dir).context("Failed to create cache directory")?;
+        let entry = Entry {
+            created: now(),
+            value,
+        };
+        let content = serde_json::to_string(&entry).context("Failed to serialize cache entry")?;
+        fs::write(self.dir.join(format!("{}.json", key)), content)
+            .context("Failed to write cache entry")
+    }
+
+    /// Remove every cached entry of every kind, returning how many there were
+    pub fn clear() -> Result<usize> {
+        clear_in(&cache_dir()?)
+    }
+}
+
+/// Remove the entries in each cache under `root`
+fn clear_in(root: &Path) -> Result<usize> {
+    let mut removed = 0;
+    for dir in read_dir(root)? {
+        if !dir.is_dir() {
+            continue;
+        }
+        for path in read_dir(&dir)? {
+            if path.extension().is_some_and(|ext| ext == "json") {
+                fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove {}", path.display()))?;
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
+
+fn cache_dir() -> Result<PathBuf> {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix("please")
+        .context("Failed to initialize XDG directories")?;
+    Ok(xdg_dirs.get_cache_home())
+}
+
+/// Paths in a directory, or none if it doesn't exist
+fn read_dir(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context("Failed to read cache directory"),
+    };
+    entries
+        .map(|entry| Ok(entry.context("Failed to read cache directory")?.path()))
+        .collect()
+}
+
+/// The SHA-256 of `bytes`, in lowercase hex
+pub fn hex_digest(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("please-cache-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        root
+    }
+
+    #[test]
+    fn entries_expire_after_the_ttl() {
+        let root = root("ttl");
+        let cache = Cache { dir: root.join("responses"), ttl: Duration::from_secs(60) };
+        cache.put("fresh", &"kept").unwrap();
+        assert_eq!(cache.get::<String>("fresh").as_deref(), Some("kept"));
+
+        let stale = Entry { created: now() - 61, value: "dropped" };
+        fs::write(cache.dir.join("stale.json"), serde_json::to_string(&stale).unwrap()).unwrap();
+        assert_eq!(cache.get::<String>("stale"), None);
+        assert!(!cache.dir.join("stale.json").exists());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn keys_depend_on_the_endpoint_and_body() {
+        let body = json!({ "model": "m", "messages": [{ "role": "user", "content": "list files" }] });
+        let key = Cache::key("https://openrouter.ai/api/v1", &body).unwrap();
+        assert_eq!(key, Cache::key("https://openrouter.ai/api/v1", &body).unwrap());
+        assert_ne!(key, Cache::key("http://localhost:11434/v1", &body).unwrap());
+
+        let redacted = json!({ "model": "m", "messages": [{ "role": "user", "content": "list files in [REDACTED]" }] });
+        assert_ne!(key, Cache::key("https://openrouter.ai/api/v1", &redacted).unwrap());
+    }
+
+    #[test]
+    fn clear_removes_entries_of_every_kind() {
+        let root = root("clear");
+        let ttl = Duration::from_secs(60);
+        let responses = Cache { dir: root.join("responses"), ttl };
+        let docs = Cache { dir: root.join("docs"), ttl };
+        responses.put("a", &1).unwrap();
+        responses.put("b", &2).unwrap();
+        docs.put("c", &3).unwrap();
+        fs::write(root.join("responses").join("notes.txt"), "kept").unwrap();
+
+        assert_eq!(clear_in(&root).unwrap(), 3);
+        assert_eq!(responses.get::<u32>("a"), None);
+        assert_eq!(docs.get::<u32>("c"), None);
+        assert!(root.join("responses").join("notes.txt").exists());
+        assert_eq!(clear_in(&root.join("missing")).unwrap(), 0);
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,372 @@
+use std::path::PathBuf;
+
+use clap::error::{ContextKind, ErrorKind};
+use clap::{Args, CommandFactory, Parser, Subcommand};
+
+use please::config::{Config, Model};
+
+const EXAMPLES: &str = "Examples:
+    please find all .rs files modified in the last 2 days
+    please search for 'TODO' in all .py files and count occurrences
+    please --model anthropic/claude-sonnet-4.5 list all running Docker containers
+    please explain tar -xzvf archive.tar.gz
+    please ask what does the sticky bit do
+    please plan set up a python venv, install requirements and run the tests
+    please history docker -d
+    please run big-files
+    please -- help me find large files";
+
+/// ask an llm for help in your closest unix shell!
+#[derive(Debug, Parser)]
+#[command(
+    name = "please",
+    version,
+    disable_help_subcommand = true,
+    override_usage = "please [OPTIONS] <REQUEST>...\n       please [OPTIONS] <COMMAND>",
+    after_help = EXAMPLES
+)]
+pub struct Cli {
+    #[command(flatten)]
+    pub overrides: Overrides,
+
+    /// Print the command for the request as JSON, with an explanation, its
+    /// risk and alternatives, instead of asking what to do with it
+    #[arg(long)]
+    pub json: bool,
+
+    /// With --json, run the command too, capturing its exit status and output
+    #[arg(long, requires = "json")]
+    pub execute: bool,
+
+    /// With --execute, run the command even when it's rated high risk
+    #[arg(long, requires = "execute")]
+    pub allow_high_risk: bool,
+
+    /// Write the request in $EDITOR, starting from any request given
+    #[arg(short, long)]
+    pub edit: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// What you'd like to do, in plain words. Everything after `--` is taken
+    /// as the request, even if it starts with a subcommand
+    #[arg(value_name = "REQUEST", trailing_var_arg = true)]
+    pub request: Vec<String>,
+}
+
+/// Flags that override the configuration for a single run
+#[derive(Debug, Args)]
+pub struct Overrides {
+    /// Model to use; repeat to give a fallback chain
+    #[arg(short, long, global = true, value_name = "MODEL")]
+    pub model: Vec<String>,
+
+    /// Endpoint URL
+    #[arg(long, global = true, value_name = "URL")]
+    pub endpoint: Option<String>,
+
+    /// Shell to generate and run commands for
+    #[arg(short, long, global = true, value_name = "SHELL")]
+    pub shell: Option<String>,
+
+    /// Suppress informational messages
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+
+    /// Ask the model even if an identical request has been cached
+    #[arg(long, global = true)]
+    pub no_cache: bool,
+
+    /// Show token usage and cost after every response
+    #[arg(long, global = true)]
+    pub show_usage: bool,
+
+    /// Print every request to stderr just before it's sent to the model
+    #[arg(long, global = true)]
+    pub show_outbound: bool,
+
+    /// Print every request to stderr instead of sending it, then stop
+    #[arg(long, global = true)]
+    pub no_send: bool,
+
+    /// Let the model inspect files and programs before answering
+    #[arg(long, global = true)]
+    pub tools: bool,
+
+    /// Ground the command in local man pages and --help output
+    #[arg(long, global = true)]
+    pub docs: bool,
+
+    /// Underline flags the programs in the command don't document
+    #[arg(long, global = true)]
+    pub check_flags: bool,
+
+    /// Prompt pack to use, from the packs directory in the config dir;
+    /// repeat to use several
+    #[arg(short, long, global = true, value_name = "NAME")]
+    pub pack: Vec<String>,
+
+    /// Generate the command for, and run it on, this host over ssh
+    #[arg(long, global = true, value_name = "USER@HOST")]
+    pub host: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Show this help message
+    Help,
+
+    /// Open the configuration file in the default editor ($EDITOR)
+    #[command(short_flag = 'C', long_flag = "config")]
+    Config,
+
+    /// Bring back the last command run, to run again or refine
+    #[command(short_flag = 'c', long_flag = "continue")]
+    Continue,
+
+    /// Explain what a shell command does
+    Explain {
+        /// The command to explain
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+
+    /// Ask a question and get an answer in prose, rather than a command
+    Ask {
+        /// The question to ask
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        question: Vec<String>,
+    },
+
+    /// Break a task into steps, then confirm and run them one by one
+    Plan {
+        /// The task to plan
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        request: Vec<String>,
+    },
+
+    /// Search the commands run so far, and run or edit one again
+    History {
+        /// Words to match against requests and commands, letters in order
+        query: Vec<String>,
+
+        /// Only show commands run in this directory (the current one if not
+        /// given, as `-d=DIR` otherwise) or below it
+        #[arg(short, long, value_name = "DIR", num_args = 0..=1, require_equals = true, default_missing_value = ".")]
+        dir: Option<PathBuf>,
+
+        /// Most entries to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    },
+
+    /// Run a saved snippet, or list them all
+    Run {
+        /// Name of the snippet
+        name: Option<String>,
+    },
+
+    /// Save the last command run as a snippet
+    Save {
+        /// Name for the snippet
+        name: String,
+    },
+
+    /// Show token usage and cost by day and month
+    Stats,
+
+    /// Manage the response cache
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+
+    /// Work with the audit log of commands run
+    Audit {
+        #[command(subcommand)]
+        action: AuditAction,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CacheAction {
+    /// Remove every cached response and piece of documentation
+    Clear,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AuditAction {
+    /// Check the audit log's hash chain for records that were changed or removed
+    Verify,
+}
+
+impl Cli {
+    /// Parse the process arguments. Words that start like a subcommand but
+    /// are followed by words it doesn't take (`please help me list files`)
+    /// are taken as a request instead of being rejected.
+    pub fn parse_args() -> Self {
+        Self::parse_from_words(std::env::args().collect()).unwrap_or_else(|error| error.exit())
+    }
+
+    fn parse_from_words(args: Vec<String>) -> Result<Self, clap::Error> {
+        let error = match Self::try_parse_from(&args) {
+            Ok(cli) => return Ok(cli),
+            Err(e) => e,
+        };
+        // only a stray word, not a missing argument or an unknown flag, means
+        // the words were a request all along
+        let word = match error.kind() {
+            ErrorKind::InvalidSubcommand => error.get(ContextKind::InvalidSubcommand),
+            ErrorKind::UnknownArgument => error.get(ContextKind::InvalidArg),
+            _ => None,
+        };
+        let stray_word = word.is_some_and(|word| !word.to_string().starts_with('-'));
+        if !stray_word {
+            return Err(error);
+        }
+
+        let Some(index) = first_positional(&args) else {
+            return Err(error);
+        };
+        let mut forced = args;
+        forced.insert(index, "--".to_string());
+        Self::try_parse_from(forced).map_err(|_| error)
+    }
+
+    pub fn print_help() {
+        // only fails if stdout is closed, in which case there's no one to tell
+        let _ = Self::command().print_help();
+    }
+}
+
+impl Overrides {
+    pub fn apply(&self, config: &mut Config) {
+        if !self.model.is_empty() {
+            config.model = self.model.iter().cloned().map(Model::from).collect();
+        }
+        if let Some(endpoint) = &self.endpoint {
+            config.endpoint = endpoint.clone();
+        }
+        if let Some(shell) = &self.shell {
+            config.shell = shell.clone();
+        }
+        if self.quiet {
+            config.quiet = true;
+        }
+        if self.no_cache {
+            config.cache.enabled = false;
+        }
+        if self.show_usage {
+            config.show_usage = true;
+        }
+        if self.show_outbound || self.no_send {
+            config.show_outbound = true;
+        }
+        if self.tools {
+            config.tools.enabled = true;
+        }
+        if self.docs {
+            config.grounding.enabled = true;
+        }
+        if self.check_flags {
+            config.check_flags.enabled = true;
+        }
+        config.prompts.packs.extend(self.pack.iter().cloned());
+        if let Some(host) = &self.host {
+            config.ssh.host = Some(host.clone());
+        }
+    }
+}
+
+/// Index in `args` of the first word that isn't an option or an option's value
+fn first_positional(args: &[String]) -> Option<usize> {
+    let command = Cli::command();
+    let takes_value = |arg: &clap::Arg| arg.get_action().takes_values();
+    let long_with_value: Vec<&str> = command
+        .get_arguments()
+        .filter(|arg| takes_value(arg))
+        .filter_map(|arg| arg.get_long())
+        .collect();
+    let short_with_value: Vec<char> = command
+        .get_arguments()
+        .filter(|arg| takes_value(arg))
+        .filter_map(|arg| arg.get_short())
+        .collect();
+
+    let mut i = 1;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        if arg == "--" {
+            return None;
+        } else if let Some(long) = arg.strip_prefix("--") {
+            if !long.contains('=') && long_with_value.contains(&long) {
+                i += 1;
+            }
+        } else if let Some(shorts) = arg.strip_prefix('-').filter(|s| !s.is_empty()) {
+            // in a cluster like `-qm`, a value-taking flag swallows the rest of
+            // the cluster or, if it comes last, the next word
+            if let Some(pos) = shorts.find(|c| short_with_value.contains(&c))
+                && pos + 1 == shorts.len()
+            {
+                i += 1;
+            }
+        } else {
+            return Some(i);
+        }
+        i += 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Cli, clap::Error> {
+        Cli::parse_from_words(line.split(' ').map(str::to_string).collect())
+    }
+
+    fn request(line: &str) -> Vec<String> {
+        let cli = parse(line).unwrap();
+        assert!(cli.command.is_none(), "{} parsed as a subcommand", line);
+        cli.request
+    }
+
+    #[test]
+    fn stray_words_after_a_subcommand_make_a_request() {
+        assert_eq!(request("please help me list files"), ["help", "me", "list", "files"]);
+        assert_eq!(request("please -q run the tests"), ["run", "the", "tests"]);
+        assert_eq!(request("please cache the results of ls"), ["cache", "the", "results", "of", "ls"]);
+    }
+
+    #[test]
+    fn subcommand_errors_are_reported() {
+        assert_eq!(parse("please explain").unwrap_err().kind(), ErrorKind::MissingRequiredArgument);
+        assert_eq!(parse("please save").unwrap_err().kind(), ErrorKind::MissingRequiredArgument);
+        assert_eq!(parse("please history --bogus").unwrap_err().kind(), ErrorKind::UnknownArgument);
+        assert!(matches!(parse("please explain ls -la").unwrap().command, Some(Command::Explain { .. })));
+    }
+
+    #[test]
+    fn pack_adds_to_configured_packs() {
+        let mut config: Config = json5::from_str("{ prompts: { packs: ['team'] } }").unwrap();
+        parse("please --pack k8s --pack ops list pods").unwrap().overrides.apply(&mut config);
+        assert_eq!(config.prompts.packs, ["team", "k8s", "ops"]);
+    }
+
+    fn history_dir(line: &str) -> (Vec<String>, Option<PathBuf>) {
+        match parse(line).unwrap().command {
+            Some(Command::History { query, dir, .. }) => (query, dir),
+            command => panic!("{} parsed as {:?}", line, command),
+        }
+    }
+
+    #[test]
+    fn history_dir_takes_a_value_only_after_equals() {
+        assert_eq!(history_dir("please history docker -d"), (vec!["docker".to_string()], Some(".".into())));
+        assert_eq!(history_dir("please history -d docker"), (vec!["docker".to_string()], Some(".".into())));
+        assert_eq!(history_dir("please history -d=/srv docker"), (vec!["docker".to_string()], Some("/srv".into())));
+        assert_eq!(history_dir("please history docker").1, None);
+    }
+}
@@ -0,0 +1,464 @@
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::process::Command;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::Result;
+use regex::Regex;
+
+use crate::cache::{hex_digest, Cache};
+use crate::config::Config;
+use crate::policy::{Policy, Verdict};
+use crate::tools::{capture, which};
+
+/// Documentation is keyed by the program's path and modification time, so
+/// entries only go stale when the program is replaced
+const CACHE_TTL: Duration = Duration::from_secs(30 * 86400);
+/// Most bytes of a man page or --help output kept before picking sections
+const MAX_SOURCE: usize = 1 << 20;
+/// Lines at the top (name, synopsis, usage) that are always kept
+const HEAD_LINES: usize = 12;
+/// Most programs looked up for a single request or command
+const MAX_PROGRAMS: usize = 4;
+
+/// Words that name programs but are far more likely to be plain English in a
+/// request
+const COMMON_WORDS: &[&str] = &[
+    "as", "at", "cut", "do", "env", "false", "file", "free", "id", "info", "install", "join", "kill", "last",
+    "less", "link", "look", "more", "read", "script", "see", "split", "test", "time", "top", "true", "users",
+    "w", "watch", "which", "who", "write", "yes",
+];
+
+/// Programs that run the program after them
+pub const WRAPPERS: &[&str] = &[
+    "sudo", "doas", "env", "nice", "nohup", "time", "xargs", "exec", "command", "watch", "busybox", "timeout",
+    "setsid", "stdbuf", "ionice", "chrt", "taskset", "nsenter", "unbuffer",
+];
+
+/// Local documentation (man pages, falling back to `--help`) for the programs
+/// a request or command involves. Programs in a generated command are only
+/// run with `--help` if they're among the configured `tools.programs`, and
+/// any program only if the system policy would let it run
+pub struct Docs {
+    cache: Cache,
+    max_bytes: usize,
+    policy: Option<Policy>,
+    programs: Vec<String>,
+}
+
+/// A flag passed to a program that its documentation doesn't mention
+pub struct UnknownFlag {
+    /// The program, as named in the command
+    pub program: String,
+    /// The flag, as written
+    pub flag: String,
+    /// Where the flag is in the command
+    pub span: Range<usize>,
+}
+
+/// The relevant parts of one program's documentation
+pub struct Excerpt {
+    /// The program documented
+    pub program: String,
+    /// Its head and the sections kept, with `[...]` where parts were left out
+    pub text: String,
+}
+
+impl Docs {
+    /// Documentation cached in `$XDG_CACHE_HOME/please/docs`, with at most
+    /// `grounding.max-bytes` of each program's sent
+    pub fn new(config: &Config) -> Result<Self> {
+        Ok(Self {
+            cache: Cache::named("docs", CACHE_TTL)?,
+            max_bytes: config.grounding.max_bytes,
+            policy: Policy::load()?,
+            programs: config.tools.programs.clone(),
+        })
+    }
+
+    /// Excerpts for each program that has documentation, keeping the sections
+    /// that mention any of `terms`. The programs may come from a generated
+    /// command, so only configured ones are run with `--help`
+    pub fn lookup(&self, programs: &[String], terms: &[String]) -> Vec<Excerpt> {
+        programs
+            .iter()
+            .filter_map(|program| self.excerpt(program, terms, self.programs.contains(program)))
+            .collect()
+    }
+
+    /// Excerpts for the programs a request names, keeping the sections that
+    /// mention its keywords. Any word can happen to name a program, so only
+    /// names in backticks are run with `--help`; the rest need a man page
+    pub fn lookup_request(&self, request: &str) -> Vec<Excerpt> {
+        let terms = keywords(request);
+        let quoted = quoted_programs(request);
+        programs_in_request(request)
+            .iter()
+            .filter_map(|program| self.excerpt(program, &terms, quoted.contains(program)))
+            .collect()
+    }
+
+    fn excerpt(&self, program: &str, terms: &[String], run_help: bool) -> Option<Excerpt> {
+        let text = self.full_text(program, run_help)?;
+        Some(Excerpt {
+            program: program.to_string(),
+            text: select(&text, terms, self.max_bytes),
+        })
+    }
+
+    /// Flags in a command that the programs they're passed to don't document.
+    /// Programs without usable documentation, and programs with subcommands
+    /// (which document their flags per subcommand), aren't checked. The
+    /// command hasn't been confirmed, so only configured programs are run
+    /// with `--help`
+    pub fn unknown_flags(&self, command: &str) -> Vec<UnknownFlag> {
+        let mut unknown = Vec::new();
+        for invocation in invocations(command) {
+            let run_help = self.programs.iter().any(|program| program == invocation.program);
+            let Some(text) = self.full_text(invocation.program, run_help) else {
+                continue;
+            };
+            let known = known_flags(&text);
+            // too little to go on, e.g. a --help that only prints a usage line
+            if known.len() < 3 {
+                continue;
+            }
+            let usage = text.lines().take(HEAD_LINES).collect::<Vec<_>>().join("\n").to_lowercase();
+            let subcommand = invocation.args.first().is_some_and(|(_, arg)| !arg.starts_with('-'));
+            if subcommand && usage.contains("command") {
+                continue;
+            }
+
+            for (start, arg) in invocation.args {
+                if arg == "--" {
+                    break;
+                }
+                let Some(name) = arg.strip_prefix('-') else {
+                    continue;
+                };
+                // `-` for stdin, and numbers like `head -5`
+                if name.is_empty() || name.chars().all(|c| c.is_ascii_digit()) {
+                    continue;
+                }
+                let flag = arg.split('=').next().unwrap_or(arg);
+                if !accepts(&known, flag) {
+                    unknown.push(UnknownFlag {
+                        program: invocation.program.to_string(),
+                        flag: flag.to_string(),
+                        span: start..start + flag.len(),
+                    });
+                }
+            }
+        }
+        unknown
+    }
+
+    /// A program's man page, or with `run_help`, its `--help` output if it
+    /// has none
+    fn full_text(&self, program: &str, run_help: bool) -> Option<String> {
+        let path = which(program).ok()?;
+        let modified = path
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_secs())
+            .unwrap_or_default();
+        let key = hex_digest(format!("{}\0{}", path.display(), modified).as_bytes());
+        if let Some(text) = self.cache.get::<String>(&key) {
+            return Some(text);
+        }
+
+        let text = man_page(program).or_else(|| {
+            let allowed = self
+                .policy
+                .as_ref()
+                .is_none_or(|policy| matches!(policy.check(&format!("{} --help", program)), Verdict::Run));
+            (run_help && allowed).then(|| help_output(program)).flatten()
+        })?;
+        // documentation that can't be cached is still worth using
+        let _ = self.cache.put(&key, &text);
+        Some(text)
+    }
+}
+
+fn man_page(program: &str) -> Option<String> {
+    let mut command = Command::new("man");
+    command.arg(program).env("MANWIDTH", "80").env_remove("MAN_KEEP_FORMATTING");
+    let (text, status) = capture(command, MAX_SOURCE).ok()?;
+    if !status?.success() {
+        return None;
+    }
+    // drop the overstrike man uses for bold and underline
+    let text = Regex::new(".\x08").unwrap().replace_all(&text, "").into_owned();
+    (!text.trim().is_empty()).then_some(text)
+}
+
+fn help_output(program: &str) -> Option<String> {
+    let mut command = Command::new(which(program).ok()?);
+    command.arg("--help");
+    let (text, status) = capture(command, MAX_SOURCE).ok()?;
+    // plenty of programs print usage to stderr and exit non-zero, so only
+    // insist on something that looks like documentation
+    status?;
+    (text.contains("-") && text.lines().count() > 2).then_some(text)
+}
+
+/// Keep the top of the documentation, then the entries that mention any of
+/// the terms, then whatever else fits, all in their original order
+fn select(text: &str, terms: &[String], max_bytes: usize) -> String {
+    let lines = text.lines().collect::<Vec<_>>();
+    let head = lines[..HEAD_LINES.min(lines.len())].join("\n");
+    let entries = entries(&lines[HEAD_LINES.min(lines.len())..]);
+
+    let mut budget = max_bytes.saturating_sub(head.len());
+    let mut chosen = BTreeSet::new();
+    let relevant = entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| terms.iter().any(|term| mentions(&entry.text, term)));
+    for (i, entry) in relevant {
+        if entry.text.len() <= budget {
+            budget -= entry.text.len();
+            chosen.insert(i);
+        }
+    }
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.text.len() > budget {
+            break;
+        }
+        if chosen.insert(i) {
+            budget -= entry.text.len();
+        }
+    }
+
+    let mut excerpt = head;
+    let mut previous = None;
+    for i in chosen {
+        if previous.is_some_and(|previous| i > previous + 1) {
+            excerpt.push_str("\n[...]\n");
+        } else {
+            excerpt.push_str(if entries[i].paragraph { "\n\n" } else { "\n" });
+        }
+        excerpt.push_str(&entries[i].text);
+        previous = Some(i);
+    }
+    if previous.is_some_and(|previous| previous + 1 < entries.len()) {
+        excerpt.push_str("\n[...]");
+    }
+    excerpt
+}
+
+/// A paragraph, or a single option with its description
+struct Entry {
+    text: String,
+    /// Whether it follows a blank line
+    paragraph: bool,
+}
+
+fn entries(lines: &[&str]) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut blank = false;
+    for line in lines {
+        if line.trim().is_empty() {
+            blank = true;
+            continue;
+        }
+        match entries.last_mut() {
+            Some(entry) if !blank && !line.trim_start().starts_with('-') => {
+                entry.text.push('\n');
+                entry.text.push_str(line);
+            }
+            _ => entries.push(Entry {
+                text: line.to_string(),
+                paragraph: blank,
+            }),
+        }
+        blank = false;
+    }
+    entries
+}
+
+/// Every flag mentioned anywhere in a program's documentation
+fn known_flags(text: &str) -> BTreeSet<String> {
+    Regex::new(r"(?:^|[\s,\[|(/])(--?[A-Za-z0-9][\w-]*)")
+        .unwrap()
+        .captures_iter(text)
+        .map(|captures| captures[1].trim_end_matches('-').to_string())
+        .collect()
+}
+
+/// Whether a flag is documented, allowing for unambiguous abbreviations of
+/// long flags and combined short flags like `-xzf`
+fn accepts(known: &BTreeSet<String>, flag: &str) -> bool {
+    if known.contains(flag) {
+        return true;
+    }
+    if flag.starts_with("--") {
+        return flag.len() >= 5 && known.iter().any(|known| known.starts_with(flag));
+    }
+    let letters = &flag[1..];
+    let mut chars = letters.chars();
+    let Some(first) = chars.next() else {
+        return false;
+    };
+    if !known.contains(&format!("-{}", first)) {
+        return false;
+    }
+    // `-n5` or `-I/usr/include`: a flag with its value attached
+    if !letters.chars().all(|c| c.is_ascii_alphabetic()) {
+        return true;
+    }
+    chars.all(|c| known.contains(&format!("-{}", c)))
+}
+
+/// Whether `term` appears in `text` as a whole word or flag, ignoring case
+/// for words
+fn mentions(text: &str, term: &str) -> bool {
+    let (text, term) = if term.starts_with('-') {
+        (text.to_string(), term.to_string())
+    } else {
+        (text.to_lowercase(), term.to_lowercase())
+    };
+    let is_word = |c: char| c.is_alphanumeric() || c == '-' || c == '_';
+    text.match_indices(&term).any(|(i, _)| {
+        let before = text[..i].chars().next_back();
+        let after = text[i + term.len()..].chars().next();
+        !before.is_some_and(is_word) && !after.is_some_and(is_word)
+    })
+}
+
+/// Installed programs a command runs, looking through pipelines, command
+/// lists, substitutions and wrappers like `sudo`
+pub fn programs_in_command(command: &str) -> Vec<String> {
+    let mut programs = Vec::new();
+    for invocation in invocations(command) {
+        push_program(&mut programs, invocation.program);
+    }
+    programs
+}
+
+/// One program run by a command
+struct Invocation<'a> {
+    program: &'a str,
+    /// The words passed to it, with where each starts in the command
+    args: Vec<(usize, &'a str)>,
+}
+
+/// Split a command into the programs it runs, looking through pipelines,
+/// command lists, substitutions and wrappers like `sudo`
+fn invocations(command: &str) -> Vec<Invocation<'_>> {
+    let separators = Regex::new(r"\|\|?|&&|;|&|\$\(|[()`\n]").unwrap();
+    let word = Regex::new(r"\S+").unwrap();
+
+    let mut segments = Vec::new();
+    let mut start = 0;
+    for separator in separators.find_iter(command) {
+        segments.push(start..separator.start());
+        start = separator.end();
+    }
+    segments.push(start..command.len());
+
+    let mut invocations = Vec::new();
+    for segment in segments {
+        let words = word
+            .find_iter(&command[segment.clone()])
+            .map(|word| (segment.start + word.start(), word.as_str()))
+            .collect::<Vec<_>>();
+        // environment assignments like `LC_ALL=C`
+        let is_assignment = |word: &str| word.contains('=') && !word.starts_with('-');
+        let Some(mut i) = words.iter().position(|(_, word)| !is_assignment(word)) else {
+            continue;
+        };
+        if WRAPPERS.contains(&words[i].1) {
+            // skip the wrapper's own flags and assignments too
+            let Some(offset) = words[i + 1..]
+                .iter()
+                .position(|(_, word)| !word.starts_with('-') && !is_assignment(word))
+            else {
+                continue;
+            };
+            i += 1 + offset;
+        }
+        invocations.push(Invocation {
+            program: words[i].1.trim_matches(|c| c == '"' || c == '\''),
+            args: words[i + 1..].to_vec(),
+        });
+    }
+    invocations
+}
+
+/// Installed programs a request names, preferring ones in backticks
+fn programs_in_request(request: &str) -> Vec<String> {
+    let mut programs = quoted_programs(request);
+    for word in request.split(|c: char| !(c.is_alphanumeric() || "._+-".contains(c))) {
+        let word = word.trim_matches('.');
+        if !COMMON_WORDS.contains(&word.to_lowercase().as_str()) {
+            push_program(&mut programs, word);
+        }
+    }
+    programs
+}
+
+/// Installed programs a request names in backticks
+fn quoted_programs(request: &str) -> Vec<String> {
+    let quoted = Regex::new(r"`([^`\s]+)").unwrap();
+    let mut programs = Vec::new();
+    for captures in quoted.captures_iter(request) {
+        push_program(&mut programs, &captures[1]);
+    }
+    programs
+}
+
+fn push_program(programs: &mut Vec<String>, word: &str) {
+    if programs.len() < MAX_PROGRAMS
+        && !word.is_empty()
+        && !word.starts_with('-')
+        && !programs.iter().any(|program| program == word)
+        && which(word).is_ok()
+    {
+        programs.push(word.to_string());
+    }
+}
+
+/// Flags used in a command, with combined short flags like `-xzf` split up
+pub fn flags_in_command(command: &str) -> Vec<String> {
+    let mut flags = Vec::new();
+    for word in command.split_whitespace() {
+        let word = word.trim_matches(|c| c == '"' || c == '\'');
+        if let Some(long) = word.strip_prefix("--") {
+            let name = long.split('=').next().unwrap_or_default();
+            if !name.is_empty() {
+                flags.push(format!("--{}", name));
+            }
+        } else if let Some(short) = word.strip_prefix('-')
+            && !short.is_empty()
+            && short.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            // keep the whole thing too, for flags like `-name` in find
+            flags.push(word.to_string());
+            if short.len() > 1 {
+                flags.extend(short.chars().map(|c| format!("-{}", c)));
+            }
+        }
+    }
+    flags
+}
+
+/// Words of a request worth looking for in documentation
+fn keywords(request: &str) -> Vec<String> {
+    request
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.len() >= 4)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Format excerpts to send to the model as context
+pub fn format(excerpts: &[Excerpt]) -> String {
+    excerpts
+        .iter()
+        .map(|excerpt| format!("Documentation for `{}`:\n```\n{}\n```", excerpt.program, excerpt.text.trim_end()))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
@@ -0,0 +1,197 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Requests longer than this (usually ones carrying piped input) aren't kept,
+/// since they'd bloat every request they were later sent along with
+const MAX_REQUEST: usize = 500;
+/// How similar a past request has to be before it's used as an example
+const MIN_SIMILARITY: f64 = 0.25;
+
+/// A request along with the command the user settled on for it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Example {
+    /// What the user asked for
+    pub request: String,
+    /// The command they ran for it
+    pub command: String,
+}
+
+/// Commands the user edited or refined before running them, kept as JSONL in
+/// `$XDG_DATA_HOME/please/examples.jsonl` to show the model next time a
+/// similar request comes along
+pub struct Examples {
+    path: PathBuf,
+}
+
+impl Examples {
+    /// The examples in `$XDG_DATA_HOME/please/examples.jsonl`
+    pub fn open() -> Result<Self> {
+        let xdg_dirs = xdg::BaseDirectories::with_prefix("please")
+            .context("Failed to initialize XDG directories")?;
+        Ok(Self {
+            path: xdg_dirs.get_data_home().join("examples.jsonl"),
+        })
+    }
+
+    /// Remember `command` as the one wanted for `request`
+    pub fn record(&self, request: &str, command: &str) -> Result<()> {
+        if request.len() > MAX_REQUEST {
+            return Ok(());
+        }
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).context("Failed to create data directory")?;
+        }
+        let example = Example {
+            request: request.to_string(),
+            command: command.to_string(),
+        };
+        let mut line = serde_json::to_string(&example).context("Failed to serialize example")?;
+        line.push('\n');
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .with_context(|| format!("Failed to write examples: {}", self.path.display()))
+    }
+
+    /// Every example, the latest for each request; lines that can't be parsed
+    /// are skipped
+    pub fn all(&self) -> Result<Vec<Example>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read examples: {}", self.path.display()));
+            }
+        };
+
+        let mut seen = HashSet::new();
+        let mut examples = content
+            .lines()
+            .rev()
+            .filter_map(|line| serde_json::from_str::<Example>(line).ok())
+            .filter(|example| seen.insert(example.request.clone()))
+            .collect::<Vec<_>>();
+        examples.reverse();
+        Ok(examples)
+    }
+
+    /// Up to `count` examples whose requests are most like `request`, by
+    /// TF-IDF cosine similarity of their words, the most similar first
+    pub fn similar(&self, request: &str, count: usize) -> Result<Vec<Example>> {
+        let examples = self.all()?;
+        if examples.is_empty() || count == 0 {
+            return Ok(Vec::new());
+        }
+
+        let documents = examples.iter().map(|example| words(&example.request)).collect::<Vec<_>>();
+        let mut frequency: HashMap<&str, usize> = HashMap::new();
+        for document in &documents {
+            for word in document.iter().collect::<HashSet<_>>() {
+                *frequency.entry(word).or_default() += 1;
+            }
+        }
+        let idf = |word: &str| {
+            let containing = frequency.get(word).copied().unwrap_or_default();
+            ((1 + documents.len()) as f64 / (1 + containing) as f64).ln() + 1.0
+        };
+        let vector = |document: &[String]| {
+            let mut vector: HashMap<String, f64> = HashMap::new();
+            for word in document {
+                *vector.entry(word.clone()).or_default() += idf(word);
+            }
+            vector
+        };
+
+        let query = vector(&words(request));
+        let mut scored = documents
+            .iter()
+            .zip(examples)
+            .map(|(document, example)| (cosine(&query, &vector(document)), example))
+            .filter(|(similarity, _)| *similarity >= MIN_SIMILARITY)
+            .collect::<Vec<_>>();
+        // stable, so the latest of equally similar examples comes first
+        scored.reverse();
+        scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        Ok(scored.into_iter().take(count).map(|(_, example)| example).collect())
+    }
+}
+
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.len() >= 2)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn cosine(a: &HashMap<String, f64>, b: &HashMap<String, f64>) -> f64 {
+    let dot = a
+        .iter()
+        .filter_map(|(word, weight)| b.get(word).map(|other| weight * other))
+        .sum::<f64>();
+    let norm = |vector: &HashMap<String, f64>| vector.values().map(|weight| weight * weight).sum::<f64>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 { 0.0 } else { dot / norms }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn examples(name: &str, pairs: &[(&str, &str)]) -> Examples {
+        let path = std::env::temp_dir().join(format!("please-examples-{}-{}.jsonl", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        let examples = Examples { path };
+        for (request, command) in pairs {
+            examples.record(request, command).unwrap();
+        }
+        examples
+    }
+
+    fn commands(examples: &Examples, request: &str, count: usize) -> Vec<String> {
+        let similar = examples.similar(request, count).unwrap();
+        similar.into_iter().map(|example| example.command).collect()
+    }
+
+    #[test]
+    fn closest_request_comes_first() {
+        let examples = examples(
+            "closest",
+            &[
+                ("show running docker containers", "docker ps"),
+                ("list docker images", "docker images"),
+                ("compress the logs directory", "tar czf logs.tgz logs"),
+            ],
+        );
+        let commands = commands(&examples, "list running docker containers", 5);
+        assert_eq!(commands[..2], ["docker ps", "docker images"]);
+        // too unlike the request to help
+        assert!(!commands.iter().any(|command| command.starts_with("tar")));
+        let _ = fs::remove_file(&examples.path);
+    }
+
+    #[test]
+    fn list_is_cut_to_max_examples() {
+        let examples = examples(
+            "limit",
+            &[
+                ("find large files in home", "find ~ -size +100M"),
+                ("find large log files", "find /var/log -size +10M"),
+                ("find large files here", "find . -size +100M"),
+                ("find large files in /srv", "find /srv -size +1G"),
+            ],
+        );
+        assert_eq!(commands(&examples, "find large files", 2).len(), 2);
+        assert_eq!(commands(&examples, "find large files", 10).len(), 4);
+        assert!(commands(&examples, "find large files", 0).is_empty());
+        let _ = fs::remove_file(&examples.path);
+    }
+}
@@ -0,0 +1,126 @@
+use std::io::{stdin, IsTerminal};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+
+use crate::audit::AuditLog;
+use crate::config::Config;
+use crate::policy::{Policy, Verdict};
+use crate::remote;
+
+/// A command about to be run, and what it was generated from
+pub struct Execution<'a> {
+    /// What the user asked for
+    pub request: &'a str,
+    /// The model that generated the command
+    pub model: &'a str,
+    /// The command as it was generated, before any edits
+    pub generated: &'a str,
+    /// The command as it will be run
+    pub command: &'a str,
+}
+
+/// What came of trying to run a command
+pub enum Outcome<T = ExitStatus> {
+    /// It ran, and exited like this (along with its output, when captured)
+    Exited(T),
+    /// The system policy only lets it be shown, for this reason
+    DryRun(String),
+}
+
+/// Run a command with the configured shell (or over ssh, when a host is
+/// configured), in the foreground, subject to the system policy and recorded
+/// in the audit log if that's enabled
+pub fn run_command(execution: &Execution, config: &Config) -> Result<Outcome> {
+    // interactive commands need a terminal at the other end too
+    run(execution, config, stdin().is_terminal(), |process| {
+        process.status().map(|status| (status, status))
+    })
+}
+
+/// Like `run_command`, but with no input and the command's output captured
+/// rather than shown
+pub fn capture_command(execution: &Execution, config: &Config) -> Result<Outcome<Output>> {
+    run(execution, config, false, |process| {
+        let output = process.stdin(Stdio::null()).output()?;
+        Ok((output.status, output))
+    })
+}
+
+/// Run a command with `wait`, which waits for it to finish and returns how it
+/// exited along with anything else it collected
+fn run<T>(
+    execution: &Execution,
+    config: &Config,
+    interactive: bool,
+    wait: impl FnOnce(&mut Command) -> std::io::Result<(ExitStatus, T)>,
+) -> Result<Outcome<T>> {
+    // the system policy applies however the command came about, generated
+    // or typed in by hand
+    let policy = Policy::load().context("Not running the command without a valid policy")?;
+    let verdict = policy.map_or(Verdict::Run, |policy| policy.check(execution.command));
+    let sandbox = match verdict {
+        Verdict::Run => None,
+        Verdict::Sandbox(sandbox) => Some(sandbox),
+        Verdict::DryRun(reason) => return Ok(Outcome::DryRun(reason)),
+        Verdict::Deny(reason) => anyhow::bail!("Refused by the system policy: {}", reason),
+    };
+    let host = config.ssh.host.as_deref();
+    if sandbox.is_some() && host.is_some() {
+        anyhow::bail!("Refused by the system policy: the command has to be sandboxed, which can't be done on a remote host");
+    }
+
+    // the audit log is opened first, so a command never runs unrecorded
+    let mut audit = if config.audit.enabled {
+        Some(AuditLog::open(&config.audit).context("Not running the command without an audit log")?)
+    } else {
+        None
+    };
+
+    let started = Instant::now();
+    let ran = (|| {
+        let mut process = match host {
+            Some(host) => remote::command(&config.ssh, host, execution.command, interactive)?,
+            None => local_command(execution.command, config, sandbox.as_deref())?,
+        };
+        wait(&mut process).with_context(|| format!("Failed to run {}", process.get_program().display()))
+    })();
+    // attempts that never got as far as running are recorded too
+    if let Some(audit) = &mut audit {
+        match &ran {
+            Ok((status, _)) => audit.record(execution, host, status, started.elapsed())?,
+            Err(e) => audit.record_failure(execution, host, &format!("{:#}", e))?,
+        }
+    }
+    Ok(Outcome::Exited(ran?.1))
+}
+
+/// The configured shell running `command`, inside `sandbox` if given
+fn local_command(command: &str, config: &Config, sandbox: Option<&str>) -> Result<Command> {
+    let shell = &config.shell;
+    // Parse the shell command (e.g., "/usr/bin/env sh" -> ["/usr/bin/env", "sh"])
+    let shell_parts: Vec<&str> = shell.split_whitespace().collect();
+
+    if shell_parts.is_empty() {
+        anyhow::bail!("Invalid shell configuration");
+    }
+
+    let shell_name = shell_parts.last().unwrap();
+
+    // Only add shopt for bash and zsh (which support it)
+    // Fish, sh, and other shells don't support shopt
+    let command = if shell_name.contains("bash") || shell_name.contains("zsh") {
+        format!("shopt -s extglob globstar nullglob\n{}", command)
+    } else {
+        command.to_string()
+    };
+
+    // a sandboxed command runs the shell inside the sandbox
+    let mut argv = sandbox.unwrap_or_default().split_whitespace().collect::<Vec<_>>();
+    argv.extend(&shell_parts);
+
+    let mut process = Command::new(argv[0]);
+    process.args(&argv[1..]).arg("-c").arg(command);
+    Ok(process)
+}
@@ -0,0 +1,220 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// One command run through `please`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// When it was run
+    pub timestamp: DateTime<Utc>,
+    /// What the user asked for
+    pub request: String,
+    /// The command as finally run, after any edits
+    pub command: String,
+    /// The model that generated it
+    pub model: String,
+    /// Exit code; `None` when the command was killed by a signal
+    pub status: Option<i32>,
+    /// The directory it was run in
+    pub cwd: PathBuf,
+}
+
+/// Every command run through `please`, kept as JSONL in
+/// `$XDG_DATA_HOME/please/history.jsonl`
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    /// The history in `$XDG_DATA_HOME/please/history.jsonl`
+    pub fn open() -> Result<Self> {
+        let xdg_dirs = xdg::BaseDirectories::with_prefix("please")
+            .context("Failed to initialize XDG directories")?;
+        Ok(Self {
+            path: xdg_dirs.get_data_home().join("history.jsonl"),
+        })
+    }
+
+    /// Append a command that was run, and its exit status
+    pub fn record(&self, request: &str, command: &str, model: &str, status: &ExitStatus) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).context("Failed to create data directory")?;
+        }
+        let entry = Entry {
+            timestamp: Utc::now(),
+            request: request.to_string(),
+            command: command.to_string(),
+            model: model.to_string(),
+            status: status.code(),
+            cwd: std::env::current_dir().unwrap_or_default(),
+        };
+        let mut line = serde_json::to_string(&entry).context("Failed to serialize history entry")?;
+        line.push('\n');
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .with_context(|| format!("Failed to write history: {}", self.path.display()))
+    }
+
+    /// Every entry, oldest first; lines that can't be parsed are skipped
+    pub fn entries(&self) -> Result<Vec<Entry>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read history: {}", self.path.display()));
+            }
+        };
+
+        Ok(content
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    /// Entries matching every word of `query` (fuzzily, against the request
+    /// and the command) that were run in `dir` or below it. The best matches
+    /// come first, most recent first among equals; repeats of a command only
+    /// show up once
+    pub fn search(&self, query: &[String], dir: Option<&Path>) -> Result<Vec<Entry>> {
+        let mut matches = Vec::new();
+        for entry in self.entries()?.into_iter().rev() {
+            if dir.is_some_and(|dir| !entry.cwd.starts_with(dir)) {
+                continue;
+            }
+            let text = format!("{}\n{}", entry.request, entry.command);
+            let score = query.iter().map(|word| fuzzy_score(word, &text)).sum::<Option<i64>>();
+            if let Some(score) = score {
+                matches.push((score, entry));
+            }
+        }
+
+        // stable, so recency still orders entries with the same score
+        matches.sort_by_key(|(score, _)| -score);
+        let mut seen = std::collections::HashSet::new();
+        Ok(matches
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| seen.insert(entry.command.clone()))
+            .collect())
+    }
+}
+
+/// Score how well `query` matches `text` as a case-insensitive subsequence,
+/// favouring consecutive characters and matches at the start of words;
+/// `None` if it doesn't match at all
+fn fuzzy_score(query: &str, text: &str) -> Option<i64> {
+    let text = text.to_lowercase().chars().collect::<Vec<_>>();
+    let query = query.to_lowercase().chars().collect::<Vec<_>>();
+    let first = *query.first()?;
+    // try the match from every place the first character appears, so an
+    // early stray match doesn't hide a better one later on
+    (0..text.len())
+        .filter(|&start| text[start] == first)
+        .filter_map(|start| score_from(&query, &text, start))
+        .max()
+}
+
+fn score_from(query: &[char], text: &[char], start: usize) -> Option<i64> {
+    let mut score = 0;
+    let mut position = start;
+    let mut previous: Option<usize> = None;
+    for &wanted in query {
+        let found = position + text[position..].iter().position(|&c| c == wanted)?;
+        score += 1;
+        if previous.is_some_and(|previous| previous + 1 == found) {
+            score += 5;
+        }
+        if found == 0 || !text[found - 1].is_alphanumeric() {
+            score += 3;
+        }
+        previous = Some(found);
+        position = found + 1;
+    }
+    Some(score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(name: &str, entries: &[(&str, &str, &str)]) -> History {
+        let path = std::env::temp_dir().join(format!("please-history-{}-{}.jsonl", std::process::id(), name));
+        let lines: String = entries
+            .iter()
+            .map(|&(request, command, cwd)| {
+                let entry = Entry {
+                    timestamp: Utc::now(),
+                    request: request.to_string(),
+                    command: command.to_string(),
+                    model: "m".to_string(),
+                    status: Some(0),
+                    cwd: PathBuf::from(cwd),
+                };
+                serde_json::to_string(&entry).unwrap() + "\n"
+            })
+            .collect();
+        fs::write(&path, lines).unwrap();
+        History { path }
+    }
+
+    fn commands(history: &History, query: &[&str], dir: Option<&str>) -> Vec<String> {
+        let query: Vec<String> = query.iter().map(|word| word.to_string()).collect();
+        let entries = history.search(&query, dir.map(Path::new)).unwrap();
+        entries.into_iter().map(|entry| entry.command).collect()
+    }
+
+    #[test]
+    fn letters_match_in_order() {
+        assert!(fuzzy_score("dkrm", "docker rm web").is_some());
+        assert!(fuzzy_score("DKRM", "docker rm web").is_some());
+        assert!(fuzzy_score("rmdk", "docker rm web").is_none());
+        assert!(fuzzy_score("dkrmx", "docker rm web").is_none());
+    }
+
+    #[test]
+    fn closer_matches_rank_first() {
+        // consecutive letters and word starts beat scattered ones
+        assert!(fuzzy_score("rm", "docker rm web") > fuzzy_score("rm", "docker run -m 1g web"));
+        assert!(fuzzy_score("log", "tail -f log") > fuzzy_score("log", "git blame go.mod"));
+
+        let history = history(
+            "rank",
+            &[
+                ("show images", "docker run --rm -m 1g alpine", "/srv"),
+                ("remove the web container", "docker rm web", "/srv"),
+                ("again", "docker rm web", "/srv"),
+                ("list files", "ls -la", "/srv"),
+            ],
+        );
+        // repeats show up once, and words that don't match leave entries out
+        assert_eq!(commands(&history, &["dkrm"], None), ["docker rm web", "docker run --rm -m 1g alpine"]);
+        assert_eq!(commands(&history, &["docker", "web"], None), ["docker rm web"]);
+        let _ = fs::remove_file(&history.path);
+    }
+
+    #[test]
+    fn dir_keeps_commands_run_in_or_below_it() {
+        let history = history(
+            "dir",
+            &[
+                ("build", "cargo build", "/home/me/project"),
+                ("test", "cargo test", "/home/me/project/crates/core"),
+                ("build the other one", "cargo build --release", "/home/me/project-two"),
+            ],
+        );
+        assert_eq!(commands(&history, &["cargo"], Some("/home/me/project")), ["cargo test", "cargo build"]);
+        assert_eq!(commands(&history, &["cargo"], Some("/home/me/project/crates")), ["cargo test"]);
+        assert_eq!(commands(&history, &["cargo"], None).len(), 3);
+        let _ = fs::remove_file(&history.path);
+    }
+}
@@ -0,0 +1,73 @@
+//! Ask an LLM for shell commands, and run them with a human in the loop.
+//!
+//! This is the library behind the `please` command line tool, for tools that
+//! want the same configuration, models and safeguards:
+//!
+//! - [`Config::load`] reads `$XDG_CONFIG_HOME/please/config.json5` and the
+//!   `PLEASE_*` environment variables.
+//! - [`ApiClient`] requests commands, refinements, plans, explanations and
+//!   answers from a fallback chain of [`Provider`]s (by default
+//!   [`HttpProvider`]s for OpenAI-compatible endpoints), with retries,
+//!   caching, redaction and usage tracking.
+//! - [`parse_command`] extracts a command from a raw model response.
+//! - [`run_command`] runs a command with the configured shell, or over ssh on
+//!   the configured host, subject to the system policy and recorded in the
+//!   audit log.
+//!
+//! ```no_run
+//! use please::{ApiClient, Config, Execution, Outcome};
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let config = Config::load()?;
+//! // retries, fallbacks and the like are only shown if there's somewhere to show them
+//! let client = ApiClient::new(&config)?.on_notice(|notice| eprintln!("{}", notice));
+//! let prompt = config.get_command_prompt();
+//! let command = client.request_command(&prompt, "list files by size", &[], &config).await?;
+//!
+//! let execution = Execution {
+//!     request: "list files by size",
+//!     model: &command.model,
+//!     generated: &command.value,
+//!     command: &command.value,
+//! };
+//! if let Outcome::Exited(status) = please::run_command(&execution, &config)? {
+//!     println!("{}", status);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+#![warn(missing_docs)]
+
+/// Requests to models, and parsing what they send back
+pub mod api;
+/// The append-only log of commands run
+pub mod audit;
+/// On-disk cache of responses and documentation
+pub mod cache;
+/// Configuration, from the config file and environment
+pub mod config;
+/// Local man pages and `--help` output, for grounding and flag checks
+pub mod docs;
+/// Commands the user settled on, kept as examples for similar requests
+pub mod examples;
+/// Running commands
+pub mod exec;
+/// Every command run, searchable
+pub mod history;
+/// The administrator's policy on what may run
+pub mod policy;
+/// Masking secrets before they reach the model
+pub mod redact;
+/// Commands for, and run on, another machine over ssh
+pub mod remote;
+/// Saved commands with placeholders
+pub mod snippets;
+/// Read-only tools the model may call
+pub mod tools;
+/// Token usage and cost, per request and over time
+pub mod usage;
+
+pub use api::{parse_command, ApiClient, ApiError, Generated, GeneratedCommand, HttpProvider, Notice, PlanStep, Provider};
+pub use config::Config;
+pub use exec::{run_command, Execution, Outcome};
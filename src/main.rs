@@ -1,130 +1,442 @@
-mod api;
-mod config;
+mod cli;
+mod markdown;
+mod stdin;
+mod terminal;
 mod ui;
 
 use anyhow::{Context, Result};
-use api::ApiClient;
-use config::Config;
-use std::{env};
-use std::process::Command;
+use cli::{AuditAction, CacheAction, Cli};
+use please::api::{ApiClient, Generated, GeneratedCommand, Risk};
+use please::audit;
+use please::cache::Cache;
+use please::config::{self, Config};
+use please::docs::{self, Docs, UnknownFlag};
+use please::examples::Examples;
+use please::exec::{capture_command, run_command, Execution, Outcome};
+use please::history::{self, History};
+use please::remote;
+use please::snippets::{Snippet, Snippets};
+use please::usage::{Ledger, Total, Usage};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::Duration;
+use stdin::Piped;
 use ui::{UserAction, UI};
+use chrono::Local;
 use colored::Colorize;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Check if user is asking for a command directly
-    let args: Vec<String> = env::args().collect();
+    let cli = Cli::parse_args();
 
     // Load configuration
-    let config = match Config::load() {
+    let mut config = match Config::load() {
         Ok(cfg) => cfg,
         Err(e) => {
             eprintln!("Failed to load configuration: {}", e);
             std::process::exit(1);
         }
     };
-
-    // Create API client
-    let api_client = ApiClient::new(
-        config.endpoint.clone(),
-        config.api_key.clone(),
-        config.model.clone(),
-    );
+    cli.overrides.apply(&mut config);
+    // nothing but the JSON goes to stdout
+    if cli.json {
+        config.quiet = true;
+    }
+    if let Err(e) = config.apply_packs() {
+        eprintln!("Failed to load prompt packs: {:#}", e);
+        std::process::exit(1);
+    }
 
     // Create UI
     let mut ui = UI::new(config.clone())?;
 
+    if config.insecure {
+        // loud on purpose, and shown even when quiet
+        eprintln!(
+            "{}",
+            "Warning: TLS certificate verification is disabled (insecure). \
+Your API key and requests can be intercepted."
+                .red()
+                .bold()
+        );
+    }
+
+    // Create API client, its notices shown around the spinner
+    let mut api_client = ApiClient::new(&config)?.on_notice(ui.notice_hook());
+    if cli.overrides.no_send {
+        api_client = api_client.without_sending();
+    }
+
+    if uses_host(&cli)
+        && let Err(e) = target_host(&ui, &mut config)
+    {
+        ui.show_error(&format!("{:#}", e));
+        std::process::exit(1);
+    }
+
     // Get the user's request
-    let user_request =  if let Some(command) = args.get(1) && args.len() == 2 {
-        match command.as_str() {
-            "--help" | "-h" | "help" => {
-                help();
-                return Ok(());
-            }
-            "--config" | "-C" | "config" => {
-                open_config(&mut ui)?;
-                return Ok(());
+    let user_request = match cli.command {
+        Some(cli::Command::Help) => {
+            Cli::print_help();
+            return Ok(());
+        }
+        Some(cli::Command::Config) => {
+            open_config(&mut ui)?;
+            return Ok(());
+        }
+        Some(cli::Command::Continue) => {
+            r#continue(&mut ui, &api_client, &config).await?;
+            return Ok(());
+        }
+        Some(cli::Command::Explain { command }) => {
+            explain(&ui, &api_client, &config, &command.join(" ")).await;
+            return Ok(());
+        }
+        Some(cli::Command::Ask { question }) => {
+            let question = with_piped_input(&ui, &config, question.join(" "))?;
+            ask(&mut ui, &api_client, &config, &question).await?;
+            return Ok(());
+        }
+        Some(cli::Command::Plan { request }) => {
+            let request = with_piped_input(&ui, &config, request.join(" "))?;
+            plan(&mut ui, &api_client, &config, &request).await?;
+            return Ok(());
+        }
+        Some(cli::Command::History { query, dir, limit }) => {
+            history(&mut ui, &api_client, &config, &query, dir, limit).await?;
+            return Ok(());
+        }
+        Some(cli::Command::Run { name }) => {
+            let snippets = Snippets::open()?;
+            match name {
+                None => {
+                    list_snippets(&ui, &snippets)?;
+                    return Ok(());
+                }
+                Some(name) => match snippets.all()?.remove(&name) {
+                    Some(snippet) => {
+                        run_snippet(&mut ui, &api_client, &config, &name, snippet).await?;
+                        return Ok(());
+                    }
+                    // `please run tests` is more likely a request than a typo
+                    None => format!("run {}", name),
+                },
             }
-            "--continue" | "-c" | "continue" => {
-                r#continue(&mut ui)?;
-                return Ok(());
+        }
+        Some(cli::Command::Save { name }) => {
+            let Some(entry) = History::open()?.entries()?.pop() else {
+                ui.show_error("Nothing in the history to save yet");
+                std::process::exit(1);
+            };
+            Snippets::open()?.save(&name, &entry.command, Some(&entry.request))?;
+            ui.show_message(&format!("Saved `{}` as {}", entry.command, name));
+            return Ok(());
+        }
+        Some(cli::Command::Stats) => {
+            stats(&ui, &config)?;
+            return Ok(());
+        }
+        Some(cli::Command::Cache { action: CacheAction::Clear }) => {
+            let removed = Cache::clear()?;
+            ui.show_message(&format!("Cleared {} cached entries", removed));
+            return Ok(());
+        }
+        Some(cli::Command::Audit { action: AuditAction::Verify }) => {
+            let verification = audit::verify(&config.audit)?;
+            if let Some(problem) = verification.problem {
+                ui.show_error(&format!("Audit log doesn't verify, at {}", problem));
+                std::process::exit(1);
             }
-            "die" | "exit" | "quit" => {
-                ui.show_message("not very nice...");
+            ui.show_message(&format!(
+                "{} records, {} of them hash chained; the chain is intact",
+                verification.records, verification.chained
+            ));
+            return Ok(());
+        }
+        None if cli.edit => match compose_request(&ui, &cli.request.join(" "))? {
+            Some(request) => request,
+            None => {
+                ui.show_message("Empty request, nothing to do");
                 return Ok(());
             }
-            _ => command.to_owned(),
+        },
+        None if cli.request.is_empty() => {
+            Cli::print_help();
+            return Ok(());
         }
-    } else if args.len() > 1 {
-        // Command line arguments provided
-        args[1..].join(" ")
-    } else {
-        help();
-        return Ok(());
+        None => cli.request.join(" "),
     };
 
+    if let "die" | "exit" | "quit" = user_request.as_str() {
+        ui.show_message("not very nice...");
+        return Ok(());
+    }
+
+    let user_request = with_piped_input(&ui, &config, user_request)?;
+
     // Get the system prompt with variables substituted
     let system_prompt = config.get_command_prompt();
 
-    // Validate that API key is set
-    if config.api_key.is_empty() {
-        anyhow::bail!(
-            "API key not found. Please set it in the config file or via PLEASE_API_KEY environment variable.\n\
-                Expected config location: ~/.config/please/config.json5"
-        );
+    check_api_key(&config)?;
+
+    if cli.json {
+        let execute = cli.execute.then_some(cli.allow_high_risk);
+        print_json(&api_client, &config, &system_prompt, &user_request, execute).await;
+        return Ok(());
     }
 
     // Request initial command from API
-    if !config.quiet {
-        ui.show_message("Thinking...");
-    }
-    let mut current_command = match api_client
-        .request_command(&system_prompt, &user_request, &config)
-        .await
-    {
-        Ok(cmd) => cmd,
+    let request = api_client.generate_command(&system_prompt, &user_request, &config);
+    let current = match ui.wait_for("Thinking...", request).await? {
+        Some(Ok(generated)) => generated,
+        Some(Err(e)) => {
+            ui.show_error(&format!("Failed to get command: {:#}", e));
+            std::process::exit(1);
+        }
+        None => return Ok(()),
+    };
+
+    confirm_and_run(&mut ui, &api_client, &config, &system_prompt, &user_request, current).await
+}
+
+/// The result of a request, as printed by `--json`
+#[derive(Serialize)]
+struct JsonResult {
+    request: String,
+    command: String,
+    alternatives: Vec<String>,
+    explanation: String,
+    risk: Risk,
+    model: String,
+    cached: bool,
+    usage: Option<Usage>,
+    /// Set when the command was run with `--execute`
+    execution: Option<JsonExecution>,
+}
+
+#[derive(Default, Serialize)]
+struct JsonExecution {
+    /// Exit code; `None` when it was killed by a signal, or never ran
+    status: Option<i32>,
+    stdout: String,
+    stderr: String,
+    /// Why the system policy only allowed a dry run, if it did
+    dry_run: Option<String>,
+    /// Why it wasn't run: refused for its risk or by the system policy, or
+    /// it couldn't be started
+    error: Option<String>,
+}
+
+/// Print the command for a request, and what it does, as JSON; failures to
+/// get a command are printed as `{"error": ...}`. With `execute`, the command
+/// is run too, if it's high risk only when `execute` holds `true`
+async fn print_json(api_client: &ApiClient, config: &Config, system_prompt: &str, request: &str, execute: Option<bool>) {
+    match json_result(api_client, config, system_prompt, request, execute).await {
+        Ok(result) => println!("{}", serde_json::to_string_pretty(&result).unwrap_or_default()),
         Err(e) => {
-            ui.show_error(&format!("Failed to get command: {}", e));
+            println!("{}", serde_json::json!({ "error": format!("{:#}", e) }));
             std::process::exit(1);
         }
+    }
+}
+
+async fn json_result(
+    api_client: &ApiClient,
+    config: &Config,
+    system_prompt: &str,
+    request: &str,
+    execute: Option<bool>,
+) -> Result<JsonResult> {
+    let generated = api_client.generate_command(system_prompt, request, config).await?;
+    let assessment = api_client
+        .assess_command(&config.get_assess_prompt(), request, &generated.value)
+        .await?;
+    let mut usage = generated.usage;
+    if let Some(assessment_usage) = &assessment.usage {
+        usage.get_or_insert_default().add(assessment_usage);
+    }
+
+    let execution = match execute {
+        None => None,
+        // nobody is asked before it runs, so the worst takes asking for
+        Some(false) if assessment.value.risk == Risk::High => Some(JsonExecution {
+            error: Some("Not run: the command is rated high risk; pass --allow-high-risk to run it".to_string()),
+            ..Default::default()
+        }),
+        Some(_) => {
+            let execution = Execution {
+                request,
+                model: &generated.model,
+                generated: &generated.value,
+                command: &generated.value,
+            };
+            // failing to run still leaves the command and its assessment to report
+            Some(match capture_command(&execution, config) {
+                Ok(Outcome::Exited(output)) => {
+                    // a history that can't be written shouldn't spoil the output
+                    let _ = History::open().and_then(|history| {
+                        history.record(request, &generated.value, &generated.model, &output.status)
+                    });
+                    JsonExecution {
+                        status: output.status.code(),
+                        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+                        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+                        ..Default::default()
+                    }
+                }
+                Ok(Outcome::DryRun(reason)) => JsonExecution {
+                    dry_run: Some(reason),
+                    ..Default::default()
+                },
+                Err(e) => JsonExecution {
+                    error: Some(format!("{:#}", e)),
+                    ..Default::default()
+                },
+            })
+        }
+    };
+
+    Ok(JsonResult {
+        request: request.to_string(),
+        command: generated.value,
+        alternatives: assessment.value.alternatives,
+        explanation: assessment.value.explanation,
+        risk: assessment.value.risk,
+        model: generated.model,
+        cached: generated.cached && assessment.cached,
+        usage,
+        execution,
+    })
+}
+
+/// List flags as `program flag`, e.g. `tar --gzip-level`
+fn describe_flags(unknown: &[UnknownFlag]) -> String {
+    unknown
+        .iter()
+        .map(|flag| format!("`{} {}`", flag.program, flag.flag))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Show a command, then run it, or let the user edit or refine it first
+async fn confirm_and_run(
+    ui: &mut UI,
+    api_client: &ApiClient,
+    config: &Config,
+    system_prompt: &str,
+    user_request: &str,
+    mut current: GeneratedCommand,
+) -> Result<()> {
+    let docs = if config.check_flags.enabled {
+        Some(Docs::new(config)?)
+    } else {
+        None
     };
+    // only correct each command once, in case the model insists on a flag
+    let mut corrected = false;
+    // whether the user has had the command refined, making it worth learning
+    let mut refined = false;
 
     // Main interaction loop
     loop {
-        match ui.display_command_and_get_action(&current_command)? {
+        let unknown = docs.as_ref().map(|docs| docs.unknown_flags(&current.value)).unwrap_or_default();
+        if let Some(docs) = &docs
+            && !unknown.is_empty()
+            && config.check_flags.auto_correct
+            && !corrected
+        {
+            corrected = true;
+            let mut programs = unknown.iter().map(|flag| flag.program.clone()).collect::<Vec<_>>();
+            programs.sort();
+            programs.dedup();
+            let flags = unknown.iter().map(|flag| flag.flag.clone()).collect::<Vec<_>>();
+            let feedback = format!(
+                "These flags are not accepted by the programs on this system: {}. \
+Fix the command using only flags from the documentation below.\n\n{}",
+                describe_flags(&unknown),
+                docs::format(&docs.lookup(&programs, &flags))
+            );
+            let request = api_client.refine_command(system_prompt, user_request, &current.value, &feedback, config);
+            match ui.wait_for("Correcting undocumented flags...", request).await? {
+                Some(Ok(new)) => {
+                    current = new;
+                    continue;
+                }
+                Some(Err(e)) => ui.show_error(&format!("Failed to correct command: {:#}", e)),
+                // show the command as it was
+                None => {}
+            }
+        }
+
+        show_cached_notice(ui, &current, config);
+        show_usage(ui, api_client, &current, config);
+        if !unknown.is_empty() {
+            ui.show_warning(&format!("Not in the documentation on this system: {}", describe_flags(&unknown)));
+        }
+        let flagged = unknown.iter().map(|flag| flag.span.clone()).collect::<Vec<_>>();
+        match ui.display_command_and_get_action(&current.value, Some(&current.model), &flagged)? {
             UserAction::RunCommand(cmd) => {
                 // Execute the command
-                run_command(&cmd, &config.shell)?;
+                let execution = Execution {
+                    request: user_request,
+                    model: &current.model,
+                    generated: &current.value,
+                    command: &cmd,
+                };
+                let Some(status) = run_and_record(ui, config, &execution)? else {
+                    break;
+                };
+                if refined && status.success() {
+                    learn(ui, config, user_request, &cmd);
+                }
+                check_status(status)?;
                 break;
             }
             UserAction::EditCommand(edited_cmd) => {
                 // User manually edited the command, run it
-                run_command(&edited_cmd, &config.shell)?;
+                let execution = Execution {
+                    request: user_request,
+                    model: &current.model,
+                    generated: &current.value,
+                    command: &edited_cmd,
+                };
+                let Some(status) = run_and_record(ui, config, &execution)? else {
+                    break;
+                };
+                if (refined || edited_cmd != current.value) && status.success() {
+                    learn(ui, config, user_request, &edited_cmd);
+                }
+                check_status(status)?;
                 break;
             }
             UserAction::ProvideFeedback(feedback) => {
                 // User provided feedback, refine the command
-                ui.show_message("Refining...");
-                match api_client
-                    .refine_command(
-                        &system_prompt,
-                        &user_request,
-                        &current_command,
-                        &feedback,
-                        &config,
-                    )
-                    .await
-                {
-                    Ok(new_cmd) => {
-                        current_command = new_cmd;
+                let request = api_client.refine_command(
+                    system_prompt,
+                    user_request,
+                    &current.value,
+                    &feedback,
+                    config,
+                );
+                match ui.wait_for("Refining...", request).await? {
+                    Some(Ok(new)) => {
+                        current = new;
+                        corrected = false;
+                        refined = true;
                     }
-                    Err(e) => {
-                        ui.show_error(&format!("Failed to refine command: {}", e));
+                    Some(Err(e)) => {
+                        ui.show_error(&format!("Failed to refine command: {:#}", e));
                         break;
                     }
+                    // back to the command before the feedback
+                    None => {}
                 }
             }
-            UserAction::Quit => {
+            UserAction::Save(name) => save_snippet(ui, &name, &current.value, user_request),
+            UserAction::Back => {}
+            UserAction::Skip | UserAction::Quit => {
                 break;
             }
         }
@@ -133,31 +445,319 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-fn help() {
-    // follow http://docopt.org/
-    println!(r#"Usage:
-    please <request>...
-    please help | -h | --help
-    please continue | -c | --continue
-    please config | -C | --config
+/// Remember the command the user settled on for a request, to show the model
+/// as an example next time
+fn learn(ui: &UI, config: &Config, request: &str, command: &str) {
+    if !config.learn.enabled {
+        return;
+    }
+    if let Err(e) = Examples::open().and_then(|examples| examples.record(request, command))
+        && !config.quiet
+    {
+        ui.show_message(&format!("{:#}", e));
+    }
+}
+
+/// Save a command as a snippet from the confirmation view
+fn save_snippet(ui: &UI, name: &str, command: &str, request: &str) {
+    match Snippets::open().and_then(|snippets| snippets.save(name, command, Some(request))) {
+        Ok(()) => ui.show_message(&format!("Saved as {}; run it with `please run {}`", name, name)),
+        Err(e) => ui.show_error(&format!("{:#}", e)),
+    }
+}
+
+fn list_snippets(ui: &UI, snippets: &Snippets) -> Result<()> {
+    let all = snippets.all()?;
+    if all.is_empty() {
+        ui.show_message("No snippets yet; type `/save <name>` when confirming a command, or run `please save <name>` after one");
+        return Ok(());
+    }
+    let width = all.keys().map(|name| name.len()).max().unwrap_or_default();
+    for (name, snippet) in &all {
+        println!("{:width$}  {}", name.bold(), snippet.command);
+        if let Some(request) = &snippet.request {
+            println!("{:width$}  {}", "", request.bright_black().italic());
+        }
+    }
+    Ok(())
+}
 
-Options:
-    -h --help       Show this help message.
-    -c --continue   Continue the last session.
-    -C --config     Open the configuration file in the default editor ($EDITOR).
+/// Fill in a snippet's placeholders, then confirm and run it
+async fn run_snippet(ui: &mut UI, api_client: &ApiClient, config: &Config, name: &str, snippet: Snippet) -> Result<()> {
+    let mut values = BTreeMap::new();
+    for placeholder in snippet.placeholders() {
+        let prompt = format!("{}: ", placeholder.name.bold());
+        let default = placeholder.default.as_deref().unwrap_or_default();
+        let Some(value) = ui.get_from_readline_with_prompt(prompt, (default, ""))? else {
+            return Ok(());
+        };
+        values.insert(placeholder.name, value);
+    }
 
-Examples:
-    please find all .rs files modified in the last 2 days
-    please search for 'TODO' in all .py files and count occurrences
-    please list all running Docker containers"#);
+    let command = GeneratedCommand {
+        value: snippet.fill(&values),
+        model: format!("snippet {}", name),
+        cached: false,
+        usage: None,
+    };
+    let request = snippet.request.unwrap_or_else(|| name.to_string());
+    warn_if_remote(ui, config);
+    let config = &replaying(config);
+    confirm_and_run(ui, api_client, config, &config.get_command_prompt(), &request, command).await
 }
 
-fn open_config(ui: &mut UI) -> Result<()> {
-    let Ok(editor) = env::var("EDITOR") else {
-        ui.show_error(r#"EDITOR environment variable not set.
-To edit the config yourself, go to $XDG_CONFIG_HOME/please/config.json5 or $HOME/.config/please/config.json5"#);
-        std::process::exit(1);
+
+async fn ask(ui: &mut UI, api_client: &ApiClient, config: &Config, question: &str) -> Result<()> {
+    check_api_key(config)?;
+    let prompt = config.get_ask_prompt();
+    let answer = match ui.wait_for("Thinking...", api_client.answer_question(&prompt, question)).await? {
+        Some(Ok(answer)) => answer,
+        Some(Err(e)) => {
+            ui.show_error(&format!("Failed to answer question: {:#}", e));
+            std::process::exit(1);
+        }
+        None => return Ok(()),
+    };
+    show_usage(ui, api_client, &answer, config);
+
+    let rendered = markdown::render(&answer.value);
+    println!("{}", rendered.text);
+    if rendered.commands.is_empty() {
+        return Ok(());
+    }
+
+    let choices = if rendered.commands.len() == 1 {
+        "1".to_string()
+    } else {
+        format!("1-{}", rendered.commands.len())
     };
+    let prompt = format!("Pick a command to run {}: ", format!("({}, Enter to skip)", choices).bright_black());
+    let choice = ui.show_prompt(prompt)?;
+    let Some(command) = choice
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_sub(1))
+        .and_then(|i| rendered.commands.get(i))
+    else {
+        return Ok(());
+    };
+
+    let selected = GeneratedCommand {
+        value: command.clone(),
+        model: answer.model,
+        cached: false,
+        usage: None,
+    };
+    confirm_and_run(ui, api_client, config, &config.get_command_prompt(), question, selected).await
+}
+
+async fn plan(ui: &mut UI, api_client: &ApiClient, config: &Config, request: &str) -> Result<()> {
+    check_api_key(config)?;
+    let prompt = config.get_plan_prompt();
+    let plan = match ui.wait_for("Planning...", api_client.request_plan(&prompt, request)).await? {
+        Some(Ok(plan)) => plan,
+        Some(Err(e)) => {
+            ui.show_error(&format!("Failed to get plan: {:#}", e));
+            std::process::exit(1);
+        }
+        None => return Ok(()),
+    };
+    show_cached_notice(ui, &plan, config);
+    show_usage(ui, api_client, &plan, config);
+
+    let model = plan.model;
+    let steps = plan.value;
+    for (i, step) in steps.iter().enumerate() {
+        println!("{} {}", format!("{}.", i + 1).bright_black(), step.description);
+        println!("   {}", step.command.cyan());
+    }
+    println!();
+
+    let command_prompt = config.get_command_prompt();
+    for (i, step) in steps.iter().enumerate() {
+        let mut command = step.command.clone();
+        loop {
+            let action = ui.display_step_and_get_action(i + 1, steps.len(), &step.description, &command)?;
+            let command_to_run = match action {
+                UserAction::RunCommand(cmd) | UserAction::EditCommand(cmd) => cmd,
+                UserAction::Skip => break,
+                UserAction::Back => continue,
+                UserAction::Quit => return Ok(()),
+                UserAction::Save(name) => {
+                    save_snippet(ui, &name, &command, &step.description);
+                    continue;
+                }
+                UserAction::ProvideFeedback(feedback) => {
+                    let step_request = format!("{}\n\nThis is the step: {}", request, step.description);
+                    let refining = api_client.refine_command(&command_prompt, &step_request, &command, &feedback, config);
+                    match ui.wait_for("Refining...", refining).await? {
+                        Some(Ok(new)) => command = new.value,
+                        Some(Err(e)) => ui.show_error(&format!("Failed to refine command: {:#}", e)),
+                        None => {}
+                    }
+                    continue;
+                }
+            };
+
+            let execution = Execution {
+                request,
+                model: &model,
+                generated: &command,
+                command: &command_to_run,
+            };
+            // a step that was only shown is done with, as far as the plan goes
+            let Some(status) = run_and_record(ui, config, &execution)? else {
+                break;
+            };
+            if status.success() {
+                break;
+            }
+
+            // offer to have the model repair the failed step, then try it again
+            ui.show_error(&format!("Step {} failed with status: {}", i + 1, status));
+            let answer = ui.show_prompt(format!("Ask for a fix? {}: ", "(y/n)".bright_black()))?;
+            if answer.trim() != "y" {
+                return Ok(());
+            }
+            let step_request = format!("{}\n\nThis is the step: {}", request, step.description);
+            let feedback = format!("That failed with {}. Fix the command.", status);
+            let repairing = api_client.refine_command(&command_prompt, &step_request, &command_to_run, &feedback, config);
+            match ui.wait_for("Repairing...", repairing).await? {
+                Some(Ok(new)) => command = new.value,
+                Some(Err(e)) => {
+                    ui.show_error(&format!("Failed to repair command: {:#}", e));
+                    return Ok(());
+                }
+                // back to the step as it was, to run or change it again
+                None => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether the command line asks the model for anything, which for a remote
+/// host means finding out what it runs first. Commands replayed from the
+/// history or snippets were written already, and the rest is all local
+fn uses_host(cli: &Cli) -> bool {
+    match &cli.command {
+        None => !cli.request.is_empty() || cli.edit,
+        // only a name that isn't a snippet becomes a request
+        Some(cli::Command::Run { name: Some(name) }) => Snippets::open()
+            .and_then(|snippets| snippets.all())
+            .is_ok_and(|snippets| !snippets.contains_key(name)),
+        Some(cli::Command::Explain { .. } | cli::Command::Ask { .. } | cli::Command::Plan { .. }) => true,
+        Some(_) => false,
+    }
+}
+
+/// Commands from the history and snippets were written for this machine, so
+/// say so before one is run somewhere else
+fn warn_if_remote(ui: &UI, config: &Config) {
+    if let Some(host) = &config.ssh.host {
+        ui.show_warning(&format!(
+            "This command was written for this machine, not {}; check it before running it there",
+            host
+        ));
+    }
+}
+
+/// With a host to run commands on over ssh, find out what it runs so commands
+/// are written for it, and turn off what would only look at this machine
+fn target_host(ui: &UI, config: &mut Config) -> Result<()> {
+    let Some(host) = config.ssh.host.clone() else {
+        return Ok(());
+    };
+    if !config.quiet {
+        ui.show_message(&format!("Looking around {}...", host));
+    }
+    let environment = remote::probe(&config.ssh, &host)?;
+
+    if config.tools.enabled || config.grounding.enabled || config.check_flags.enabled {
+        if !config.quiet {
+            ui.show_message("Tools, docs and flag checks only look at this machine, so they're off for remote hosts");
+        }
+        config.tools.enabled = false;
+        config.grounding.enabled = false;
+        config.check_flags.enabled = false;
+    }
+    config.shell = environment.shell.clone();
+    config.add_to_prompts(&environment.describe(&host));
+    Ok(())
+}
+
+/// Attach anything piped into stdin to the request as context
+fn with_piped_input(ui: &UI, config: &Config, request: String) -> Result<String> {
+    let timeout = Duration::try_from_secs_f64(config.stdin_timeout)
+        .with_context(|| format!("Invalid stdin-timeout: {}", config.stdin_timeout))?;
+    let input = match stdin::read_piped(config.stdin_limit, timeout)? {
+        Piped::Input(input) => input,
+        Piped::Skipped => {
+            if !config.quiet {
+                ui.show_message(&format!(
+                    "Nothing was piped in within {}s, so stdin was skipped (stdin-timeout)",
+                    config.stdin_timeout
+                ));
+            }
+            return Ok(request);
+        }
+        Piped::Nothing => return Ok(request),
+    };
+    if input.truncated > 0 && !config.quiet {
+        ui.show_message(&format!(
+            "Piped input truncated to {} bytes (stdin-limit)",
+            config.stdin_limit
+        ));
+    }
+    Ok(input.attach_to(&request))
+}
+
+/// Validate that API key is set for the primary model
+fn check_api_key(config: &Config) -> Result<()> {
+    if config.model[0].api_key.as_ref().unwrap_or(&config.api_key).is_empty() {
+        anyhow::bail!(
+            "API key not found. Please set it in the config file or via PLEASE_API_KEY environment variable.\n\
+                Expected config location: ~/.config/please/config.json5"
+        );
+    }
+    Ok(())
+}
+
+async fn explain(ui: &UI, api_client: &ApiClient, config: &Config, command: &str) {
+    if let Err(e) = check_api_key(config) {
+        ui.show_error(&format!("{:#}", e));
+        std::process::exit(1);
+    }
+    let prompt = config.get_explain_prompt();
+    match ui.wait_for("Thinking...", api_client.explain_command(&prompt, command)).await {
+        Ok(Some(Ok(explanation))) => println!("{}", explanation.value),
+        Ok(Some(Err(e))) | Err(e) => {
+            ui.show_error(&format!("Failed to explain command: {:#}", e));
+            std::process::exit(1);
+        }
+        Ok(None) => {}
+    }
+}
+
+/// Have the user write their request in $EDITOR, for ones too long or
+/// detailed to type on the command line. `None` if they left it empty
+fn compose_request(ui: &UI, initial: &str) -> Result<Option<String>> {
+    let template = format!(
+        "{}\n\n# What would you like to do? Lines starting with # are ignored, and\n# an empty request does nothing.",
+        initial
+    );
+    let written = ui.edit_in_editor(&template, "txt")?;
+    let request = written
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let request = request.trim();
+    Ok((!request.is_empty()).then(|| request.to_string()))
+}
+
+fn open_config(ui: &mut UI) -> Result<()> {
     let xdg_dirs = xdg::BaseDirectories::with_prefix("please")
         .context("Failed to initialize XDG directories")?;
 
@@ -166,68 +766,204 @@ To edit the config yourself, go to $XDG_CONFIG_HOME/please/config.json5 or $HOME
         .find_config_file("config.json5")
         .or_else(|| xdg_dirs.find_config_file("config.json"));
 
-    if let Some(path) = config_path {
-        Command::new(editor)
-            .arg(path)
-            .status()
-            .context("Failed to open config file in editor")?;
-        Ok(())
-    } else if ui.show_prompt(format!("No config file found. Create one now? {}: ", "(y/n)".bright_black()))? == "y" {
-        let config_dir = xdg_dirs
-            .get_config_home();
-        std::fs::create_dir_all(&config_dir)
-            .context("Failed to create config directory")?;
-        let config_file_path = config_dir.join("config.json5");
-        std::fs::write(&config_file_path, config::DEFAULT_CONFIG_FILE)
-            .context("Failed to write default config file")?;
-        Command::new(editor)
-            .arg(config_file_path)
-            .status()
-            .context("Failed to open config file in editor")?;
-        Ok(())
-    } else {
-        Ok(())
+    let path = match config_path {
+        Some(path) => path,
+        None if ui.show_prompt(format!("No config file found. Create one now? {}: ", "(y/n)".bright_black()))? == "y" => {
+            let config_dir = xdg_dirs
+                .get_config_home();
+            std::fs::create_dir_all(&config_dir)
+                .context("Failed to create config directory")?;
+            let config_file_path = config_dir.join("config.json5");
+            std::fs::write(&config_file_path, config::DEFAULT_CONFIG_FILE)
+                .context("Failed to write default config file")?;
+            config_file_path
+        }
+        None => return Ok(()),
+    };
+    ui.open_in_editor(&path)
+        .with_context(|| format!("To edit the config yourself, open {}", path.display()))
+}
+
+fn show_cached_notice<T>(ui: &UI, generated: &Generated<T>, config: &Config) {
+    if generated.cached && !config.quiet {
+        ui.show_message("(cached response; run with --no-cache or refine to ask again)");
+    }
+}
+
+fn show_usage<T>(ui: &UI, api_client: &ApiClient, generated: &Generated<T>, config: &Config) {
+    if !config.show_usage || config.quiet {
+        return;
     }
+    let Some(usage) = &generated.usage else {
+        return;
+    };
+    let session = api_client.session_usage();
+    ui.show_message(&format!(
+        "{} prompt + {} completion tokens{} (session: {} tokens{})",
+        usage.prompt_tokens,
+        usage.completion_tokens,
+        format_cost(usage, ", "),
+        session.total_tokens(),
+        format_cost(&session, ", "),
+    ));
 }
 
-fn run_command(command: &str, shell: &str) -> Result<()> {
-    // Parse the shell command (e.g., "/usr/bin/env sh" -> ["/usr/bin/env", "sh"])
-    let shell_parts: Vec<&str> = shell.split_whitespace().collect();
+fn format_cost(usage: &Usage, prefix: &str) -> String {
+    usage
+        .cost
+        .map(|cost| format!("{}${:.4}", prefix, cost))
+        .unwrap_or_default()
+}
 
-    if shell_parts.is_empty() {
-        anyhow::bail!("Invalid shell configuration");
+fn stats(ui: &UI, config: &Config) -> Result<()> {
+    let ledger = Ledger::open()?;
+    let monthly = ledger.totals("%Y-%m")?;
+    if monthly.is_empty() {
+        ui.show_message("No usage recorded yet.");
+        return Ok(());
     }
 
-    let shell_name = shell_parts.last().unwrap();
+    let this_month = Local::now().format("%Y-%m").to_string();
+    let daily = ledger.totals("%Y-%m-%d")?;
 
-    // Only add shopt for bash and zsh (which support it)
-    // Fish, sh, and other shells don't support shopt
-    let command = if shell_name.contains("bash") || shell_name.contains("zsh") {
-        format!("shopt -s extglob globstar nullglob\n{command}")
-    } else {
-        command.to_string()
-    };
+    println!("{}", "Daily (this month)".bold());
+    print_totals(daily.iter().filter(|((day, _), _)| day.starts_with(&this_month)));
+    println!();
+    println!("{}", "Monthly".bold());
+    print_totals(monthly.iter());
 
-    let status = if shell_parts.len() == 1 {
-        Command::new(shell_parts[0])
-            .arg("-c")
-            .arg(command)
-            .status()?
-    } else {
-        Command::new(shell_parts[0])
-            .args(&shell_parts[1..])
-            .arg("-c")
-            .arg(command)
-            .status()?
-    };
+    if let Some(budget) = config.monthly_budget {
+        println!();
+        println!("Budget: ${:.4} of ${:.2} spent this month", ledger.month_cost()?, budget);
+    }
+    Ok(())
+}
+
+fn print_totals<'a>(totals: impl Iterator<Item = (&'a (String, String), &'a Total)>) {
+    let header = format!(
+        "  {:<10}  {:<36} {:>8} {:>10} {:>10} {:>10}",
+        "period", "model", "requests", "prompt", "completion", "cost"
+    );
+    println!("{}", header.bright_black());
+    for ((period, model), total) in totals {
+        let cost = total
+            .usage
+            .cost
+            .map(|cost| format!("${:.4}", cost))
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "  {:<10}  {:<36} {:>8} {:>10} {:>10} {:>10}",
+            period, model, total.requests, total.usage.prompt_tokens, total.usage.completion_tokens, cost
+        );
+    }
+}
 
+fn check_status(status: ExitStatus) -> Result<()> {
     if !status.success() {
         anyhow::bail!("Command failed with status: {}", status);
     }
-
     Ok(())
 }
 
-fn r#continue(ui: &mut UI) -> Result<()> {
-    todo!("implement continue functionality")
+/// Bring back the last command run, to run again or refine
+async fn r#continue(ui: &mut UI, api_client: &ApiClient, config: &Config) -> Result<()> {
+    let Some(entry) = History::open()?.entries()?.pop() else {
+        ui.show_message("Nothing in the history yet");
+        return Ok(());
+    };
+    if !config.quiet {
+        ui.show_message(&format!("Last request: {}", entry.request));
+    }
+    reopen(ui, api_client, config, entry).await
+}
+
+async fn history(
+    ui: &mut UI,
+    api_client: &ApiClient,
+    config: &Config,
+    query: &[String],
+    dir: Option<PathBuf>,
+    limit: usize,
+) -> Result<()> {
+    let dir = dir
+        .map(|dir| dir.canonicalize().with_context(|| format!("No such directory: {}", dir.display())))
+        .transpose()?;
+    let mut entries = History::open()?.search(query, dir.as_deref())?;
+    if entries.is_empty() {
+        ui.show_message("No matching commands in the history");
+        return Ok(());
+    }
+    entries.truncate(limit);
+
+    for (i, entry) in entries.iter().enumerate() {
+        let status = match entry.status {
+            Some(0) => "✓".green(),
+            Some(code) => format!("✗ {}", code).red(),
+            None => "✗".red(),
+        };
+        let when = entry.timestamp.with_timezone(&Local).format("%Y-%m-%d %H:%M");
+        println!("{:>3}  {}  {}  {}", i + 1, when.to_string().bright_black(), entry.command, status);
+        let context = format!("{} · {}", entry.request, entry.cwd.display());
+        println!("     {}", context.bright_black().italic());
+    }
+
+    let choices = if entries.len() == 1 {
+        "1".to_string()
+    } else {
+        format!("1-{}", entries.len())
+    };
+    let prompt = format!("Pick a command {}: ", format!("({}, Enter to skip)", choices).bright_black());
+    let choice = ui.show_prompt(prompt)?;
+    let Some(entry) = choice
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_sub(1))
+        .and_then(|i| entries.get(i))
+    else {
+        return Ok(());
+    };
+    reopen(ui, api_client, config, entry.clone()).await
+}
+
+/// Show a command from the history in the usual confirmation view; running
+/// or editing it doesn't involve the API, refining it does
+async fn reopen(ui: &mut UI, api_client: &ApiClient, config: &Config, entry: history::Entry) -> Result<()> {
+    warn_if_remote(ui, config);
+    let command = GeneratedCommand {
+        value: entry.command,
+        model: entry.model,
+        cached: false,
+        usage: None,
+    };
+    let config = &replaying(config);
+    confirm_and_run(ui, api_client, config, &config.get_command_prompt(), &entry.request, command).await
+}
+
+/// The configuration for showing a command settled on before, which only
+/// involves the API once the user asks for a refinement: undocumented flags
+/// are underlined, not corrected
+fn replaying(config: &Config) -> Config {
+    let mut config = config.clone();
+    config.check_flags.auto_correct = false;
+    config
+}
+
+/// Run a command and add it to the history. `None` when the system policy
+/// only let it be shown, which leaves the history alone
+fn run_and_record(ui: &UI, config: &Config, execution: &Execution) -> Result<Option<ExitStatus>> {
+    let status = match run_command(execution, config)? {
+        Outcome::Exited(status) => status,
+        Outcome::DryRun(reason) => {
+            ui.show_warning(&format!("Dry run only ({}), not running: {}", reason, execution.command));
+            return Ok(None);
+        }
+    };
+    // a history that can't be written shouldn't fail the command
+    let recorded = History::open()
+        .and_then(|history| history.record(execution.request, execution.command, execution.model, &status));
+    if let Err(e) = recorded && !config.quiet {
+        ui.show_message(&format!("{:#}", e));
+    }
+    Ok(Some(status))
 }
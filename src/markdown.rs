@@ -0,0 +1,215 @@
+use colored::Colorize;
+use pulldown_cmark::{CodeBlockKind, Event, Parser, Tag, TagEnd};
+
+/// Markdown formatted for the terminal, along with the shell commands found
+/// in its code blocks (numbered in the output, starting from 1)
+pub struct Rendered {
+    pub text: String,
+    pub commands: Vec<String>,
+}
+
+/// Code block languages whose contents are treated as runnable commands
+const SHELL_LANGUAGES: &[&str] = &["", "sh", "bash", "zsh", "fish", "shell", "console", "shell-session"];
+
+#[derive(Default)]
+struct State {
+    bold: usize,
+    italic: usize,
+    heading: bool,
+    quote_depth: usize,
+    /// Next number for each nested list; `None` for bulleted lists
+    lists: Vec<Option<u64>>,
+    /// Language and contents of the code block being read
+    code: Option<(String, String)>,
+    /// Destination and start of the text of each link being read
+    links: Vec<(String, usize)>,
+}
+
+impl State {
+    fn style(&self, text: &str) -> String {
+        let mut styled = text.normal();
+        if self.heading || self.bold > 0 {
+            styled = styled.bold();
+        }
+        if self.heading {
+            styled = styled.underline();
+        }
+        if self.italic > 0 {
+            styled = styled.italic();
+        }
+        styled.to_string()
+    }
+
+    fn newline(&self, out: &mut String) {
+        out.push('\n');
+        for _ in 0..self.quote_depth {
+            out.push_str(&"│ ".bright_black().to_string());
+        }
+    }
+
+    /// End a block, leaving a blank line before the next one
+    fn end_block(&self, out: &mut String) {
+        if self.lists.is_empty() {
+            self.newline(out);
+            self.newline(out);
+        }
+    }
+}
+
+pub fn render(markdown: &str) -> Rendered {
+    let mut out = String::new();
+    let mut commands = Vec::new();
+    let mut state = State::default();
+
+    for event in Parser::new(markdown) {
+        match event {
+            Event::Start(Tag::Heading { .. }) => state.heading = true,
+            Event::End(TagEnd::Heading(_)) => {
+                state.heading = false;
+                state.end_block(&mut out);
+            }
+            Event::End(TagEnd::Paragraph) => state.end_block(&mut out),
+            Event::Start(Tag::Strong) => state.bold += 1,
+            Event::End(TagEnd::Strong) => state.bold -= 1,
+            Event::Start(Tag::Emphasis) => state.italic += 1,
+            Event::End(TagEnd::Emphasis) => state.italic -= 1,
+            Event::Start(Tag::BlockQuote(_)) => {
+                state.quote_depth += 1;
+                out.push_str(&"│ ".bright_black().to_string());
+            }
+            Event::End(TagEnd::BlockQuote(_)) => {
+                // drop the prefixes already written for lines that won't come
+                let prefix = "│ ".bright_black().to_string();
+                loop {
+                    let trimmed = out.trim_end_matches('\n');
+                    match trimmed.strip_suffix(&prefix) {
+                        Some(rest) => out.truncate(rest.len()),
+                        None => {
+                            out.truncate(trimmed.len());
+                            break;
+                        }
+                    }
+                }
+                state.quote_depth -= 1;
+                state.end_block(&mut out);
+            }
+            Event::Start(Tag::List(start)) => {
+                if !state.lists.is_empty() && !out.ends_with('\n') {
+                    state.newline(&mut out);
+                }
+                state.lists.push(start);
+            }
+            Event::End(TagEnd::List(_)) => {
+                state.lists.pop();
+                if state.lists.is_empty() {
+                    state.newline(&mut out);
+                }
+            }
+            Event::Start(Tag::Item) => {
+                let indent = "  ".repeat(state.lists.len().saturating_sub(1));
+                let marker = match state.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}.", *number - 1)
+                    }
+                    _ => "•".to_string(),
+                };
+                out.push_str(&format!("{}{} ", indent, marker));
+            }
+            Event::End(TagEnd::Item) if !out.ends_with('\n') => state.newline(&mut out),
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let language = match kind {
+                    CodeBlockKind::Fenced(language) => language.trim().to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                state.code = Some((language, String::new()));
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                let Some((language, code)) = state.code.take() else {
+                    continue;
+                };
+                if SHELL_LANGUAGES.contains(&language.as_str()) && !code.trim().is_empty() {
+                    commands.push(strip_prompts(&code, &language));
+                    let label = format!("[{}] {}", commands.len(), language);
+                    out.push_str(&label.trim_end().bright_black().to_string());
+                    state.newline(&mut out);
+                }
+                for line in code.trim_end().lines() {
+                    out.push_str(&format!("    {}", line.cyan()));
+                    state.newline(&mut out);
+                }
+                if state.lists.is_empty() {
+                    state.newline(&mut out);
+                }
+            }
+            Event::Start(Tag::Link { dest_url, .. }) => {
+                state.links.push((dest_url.to_string(), out.len()));
+            }
+            Event::End(TagEnd::Link) => {
+                let Some((url, start)) = state.links.pop() else {
+                    continue;
+                };
+                if !out[start..].contains(url.as_str()) {
+                    out.push_str(&format!(" ({})", url).bright_black().to_string());
+                }
+            }
+            Event::Text(text) => match &mut state.code {
+                Some((_, code)) => code.push_str(&text),
+                None => out.push_str(&state.style(&text)),
+            },
+            Event::Code(code) => out.push_str(&code.yellow().to_string()),
+            Event::SoftBreak => out.push(' '),
+            Event::HardBreak => state.newline(&mut out),
+            Event::Rule => {
+                out.push_str(&"─".repeat(40).bright_black().to_string());
+                state.end_block(&mut out);
+            }
+            _ => {}
+        }
+    }
+
+    Rendered {
+        text: out.trim_end().to_string(),
+        commands,
+    }
+}
+
+/// Drop the `$ ` prompts models like to put in front of commands. A
+/// `console` or `shell-session` block is a transcript, so only its prompted
+/// lines are commands and the rest is their output
+fn strip_prompts(code: &str, language: &str) -> String {
+    let lines = code.trim().lines();
+    if matches!(language, "console" | "shell-session") {
+        let prompted = lines
+            .clone()
+            .filter_map(|line| line.strip_prefix("$ ").or_else(|| line.strip_prefix("# ")))
+            .collect::<Vec<_>>();
+        // a transcript without prompts is just commands
+        if !prompted.is_empty() {
+            return prompted.join("\n");
+        }
+    }
+    lines
+        .map(|line| line.strip_prefix("$ ").unwrap_or(line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn console_blocks_keep_only_prompted_lines() {
+        let markdown = "```console\n$ ls /srv\nbackups  www\n# rm -rf /srv/backups\n$ df -h\nFilesystem  Size\n```\n";
+        assert_eq!(render(markdown).commands, ["ls /srv\nrm -rf /srv/backups\ndf -h"]);
+    }
+
+    #[test]
+    fn shell_blocks_keep_every_line() {
+        let markdown = "```sh\n$ cd /srv\ntar czf backups.tgz backups\n```\n";
+        assert_eq!(render(markdown).commands, ["cd /srv\ntar czf backups.tgz backups"]);
+        // a console block without prompts is just commands
+        assert_eq!(render("```console\nuptime\n```\n").commands, ["uptime"]);
+    }
+}
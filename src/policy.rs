@@ -0,0 +1,506 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::docs::WRAPPERS;
+
+/// Where administrators put the policy. It's deliberately not part of
+/// `Config`, so nothing a user sets can move or override it
+pub const POLICY_FILE: &str = "/etc/please/policy.json5";
+
+/// Programs that run commands given to them as arguments, which are checked
+/// too (as far as they can be read without running anything)
+const INTERPRETERS: &[&str] = &["sh", "bash", "zsh", "dash", "ksh", "fish", "eval", "source", "."];
+
+/// Programs that run scripts in languages other than the shell's. Their
+/// scripts can't be split into commands, so every word of one is checked
+/// against the deny rules as if it were a program
+const SCRIPT_INTERPRETERS: &[&str] = &[
+    "python", "python2", "python3", "perl", "ruby", "node", "php", "lua", "tclsh", "awk", "gawk", "mawk",
+    "nawk",
+];
+
+/// Words that open or close a compound command (`if`, loops, `{ ... }`) or
+/// negate one, with the program that actually runs after them
+const RESERVED_WORDS: &[&str] = &["!", "{", "}", "if", "then", "elif", "else", "fi", "while", "until", "do", "done", "esac"];
+
+/// The actions of `find` that run a command, up to a `;` or `+`
+const FIND_ACTIONS: &[&str] = &["-exec", "-execdir", "-ok", "-okdir"];
+
+/// Options of wrappers that take a value as the next word, which would
+/// otherwise be taken for the program they run
+const WRAPPER_OPTIONS: &[(&str, &[&str])] = &[
+    ("sudo", &["-u", "-g", "-h", "-p", "-C", "-D", "-r", "-t", "-U", "-T"]),
+    ("doas", &["-u", "-C"]),
+    ("env", &["-u", "-C"]),
+    ("nice", &["-n"]),
+    ("time", &["-f", "-o"]),
+    ("xargs", &["-a", "-d", "-E", "-I", "-L", "-n", "-P", "-s"]),
+    ("exec", &["-a"]),
+    ("watch", &["-n"]),
+    ("timeout", &["-s", "-k"]),
+    ("stdbuf", &["-i", "-o", "-e"]),
+    ("ionice", &["-c", "-n", "-p", "-P", "-u"]),
+    ("nsenter", &["-t", "-S", "-G"]),
+];
+
+/// How many words wrappers take after their flags before the program they
+/// run, like the duration of `timeout`
+const WRAPPER_OPERANDS: &[(&str, usize)] = &[("timeout", 1), ("chrt", 1), ("taskset", 1)];
+
+/// Restrictions on which commands may run, set by an administrator in
+/// `/etc/please/policy.json5`. Each rule names a program (`"*"` for any) and
+/// optionally a regex its arguments must match. Commands are read without
+/// running them, so deny rules are best-effort: a shell or script can always
+/// find another way to run a program, and only an allow list is strict
+///
+/// ```json5
+/// {
+///     // best-effort: see above
+///     "deny": ["shutdown", { "program": "rm", "args": "--no-preserve-root", "reason": "no" }],
+///     "allow": ["ls", "git", "kubectl"],
+///     "dry-run": [{ "program": "kubectl", "args": "\\b(delete|drain)\\b" }],
+///     "sandbox": ["curl"],
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct Policy {
+    /// Commands that are never run
+    #[serde(default)]
+    deny: Vec<Rule>,
+
+    /// If any are given, only commands whose every program matches one of
+    /// these are run
+    #[serde(default)]
+    allow: Vec<Rule>,
+
+    /// Commands that are only shown, never run
+    #[serde(default, rename = "dry-run")]
+    dry_run: Vec<Rule>,
+
+    /// Commands that are run inside `sandbox-command`
+    #[serde(default)]
+    sandbox: Vec<Rule>,
+
+    /// What sandboxed commands are run under; the shell and the command are
+    /// appended to it
+    #[serde(default = "default_sandbox_command", rename = "sandbox-command")]
+    sandbox_command: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "RuleSpec")]
+struct Rule {
+    program: String,
+    args: Option<Regex>,
+    reason: Option<String>,
+}
+
+/// A rule as written: just a program's name, or the whole thing
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RuleSpec {
+    Program(String),
+    Rule {
+        program: String,
+        #[serde(default)]
+        args: Option<String>,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+}
+
+impl TryFrom<RuleSpec> for Rule {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: RuleSpec) -> Result<Self> {
+        let (program, args, reason) = match spec {
+            RuleSpec::Program(program) => (program, None, None),
+            RuleSpec::Rule { program, args, reason } => (program, args, reason),
+        };
+        let args = args
+            .map(|args| Regex::new(&args).with_context(|| format!("Invalid args pattern: {}", args)))
+            .transpose()?;
+        Ok(Self { program, args, reason })
+    }
+}
+
+/// What the policy says to do with a command
+pub enum Verdict {
+    /// Run it as it is
+    Run,
+    /// Run it, prefixed with this sandbox command
+    Sandbox(String),
+    /// Show it without running it, for this reason
+    DryRun(String),
+    /// Refuse to run it, for this reason
+    Deny(String),
+}
+
+/// One program a command runs, and the arguments passed to it
+struct Invocation {
+    program: String,
+    args: String,
+    /// Only named in a script, so it may not be a program at all; it's held
+    /// to the deny rules but not the allow list
+    mentioned: bool,
+}
+
+impl Policy {
+    /// The system policy, if there is one. A policy that can't be read or
+    /// parsed, or that users could have written themselves, is an error
+    /// rather than being ignored
+    pub fn load() -> Result<Option<Self>> {
+        let path = Path::new(POLICY_FILE);
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read policy: {}", POLICY_FILE)),
+        };
+        if metadata.uid() != 0 || metadata.mode() & 0o022 != 0 {
+            anyhow::bail!(
+                "{} must be owned by root and writable only by its owner",
+                POLICY_FILE
+            );
+        }
+
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read policy: {}", POLICY_FILE))?;
+        json5::from_str(&content)
+            .map(Some)
+            .with_context(|| format!("Failed to parse policy: {}", POLICY_FILE))
+    }
+
+    /// Check every program a command runs, including ones behind wrappers
+    /// like `sudo`. A denial anywhere wins, then dry runs, then sandboxing
+    pub fn check(&self, command: &str) -> Verdict {
+        let invocations = invocations(command);
+
+        for invocation in &invocations {
+            if let Some(rule) = find(&self.deny, invocation) {
+                return Verdict::Deny(reason(rule, &format!("{} is denied", invocation.program)));
+            }
+            // a name built by expansion can't be known before it runs
+            if invocation.program.contains('$') && !(self.deny.is_empty() && self.allow.is_empty()) {
+                return Verdict::Deny(format!("{} can't be checked before it runs", invocation.program));
+            }
+            if invocation.mentioned {
+                continue;
+            }
+            if !self.allow.is_empty() && find(&self.allow, invocation).is_none() {
+                return Verdict::Deny(format!("{} is not allowed", invocation.program));
+            }
+        }
+        for invocation in &invocations {
+            if let Some(rule) = find(&self.dry_run, invocation) {
+                return Verdict::DryRun(reason(rule, &format!("{} is dry-run only", invocation.program)));
+            }
+        }
+        if invocations.iter().any(|invocation| find(&self.sandbox, invocation).is_some()) {
+            return Verdict::Sandbox(self.sandbox_command.clone());
+        }
+        Verdict::Run
+    }
+}
+
+fn find<'a>(rules: &'a [Rule], invocation: &Invocation) -> Option<&'a Rule> {
+    rules.iter().find(|rule| {
+        (rule.program == "*" || rule.program == invocation.program)
+            && rule.args.as_ref().is_none_or(|args| args.is_match(&invocation.args))
+    })
+}
+
+fn reason(rule: &Rule, default: &str) -> String {
+    rule.reason.clone().unwrap_or_else(|| default.to_string())
+}
+
+/// Split a command into the programs it runs, looking through pipelines,
+/// command lists, substitutions and `find -exec`. Unlike the split used for
+/// documentation, wrappers like `sudo` count as programs too, along with
+/// the program they run. Reserved words like `if`, `do` and `{` are looked
+/// past, and `for` lists and `case` patterns are skipped. Separators inside
+/// quotes split the command as well, which can only add programs to check,
+/// never hide them
+fn invocations(command: &str) -> Vec<Invocation> {
+    let separators = Regex::new(r"\|\|?|&&|;|&|\$\(|<\(|>\(|[()`\n]").unwrap();
+    let word = Regex::new(r"\S+").unwrap();
+    let script_word = Regex::new(r"[\w.+-]+").unwrap();
+    let is_assignment = |word: &str| word.contains('=') && !word.starts_with('-');
+
+    // each segment along with the separator that ends it
+    let mut segments = Vec::new();
+    let mut start = 0;
+    for separator in separators.find_iter(command) {
+        segments.push((start..separator.start(), separator.as_str()));
+        start = separator.end();
+    }
+    segments.push((start..command.len(), ""));
+
+    let mut invocations = Vec::new();
+    // `case` statements being read, and whether a pattern comes next
+    let mut cases = 0;
+    let mut pattern_next = false;
+    let mut previous_separator = "";
+    for (segment, separator) in segments {
+        let blank = command[segment.clone()].trim().is_empty();
+        let after = std::mem::replace(&mut previous_separator, separator);
+        // each word, and where it ends in the command
+        let (ends, words): (Vec<_>, Vec<_>) = word
+            .find_iter(&command[segment.clone()])
+            .map(|word| (segment.start + word.end(), word.as_str()))
+            .unzip();
+
+        if cases > 0 {
+            // the nothing between the two `;` of `;;`, ending a branch
+            if blank && separator == ";" && after == ";" {
+                pattern_next = true;
+                continue;
+            }
+            // a pattern, up to its `)`, possibly with alternatives and an
+            // opening `(`
+            if pattern_next && words.first() != Some(&"esac") {
+                if separator == ")" {
+                    pattern_next = false;
+                    continue;
+                }
+                if blank || separator == "|" {
+                    continue;
+                }
+                pattern_next = false;
+            }
+        }
+
+        let mut i = 0;
+        while let Some(&word) = words.get(i).filter(|word| RESERVED_WORDS.contains(word)) {
+            if word == "esac" {
+                cases -= 1;
+            }
+            i += 1;
+        }
+        match words.get(i) {
+            // the words a loop goes over aren't run
+            Some(&"for" | &"select") => continue,
+            // the first pattern may be in the same segment, ended by its `)`
+            Some(&"case") => {
+                cases += 1;
+                pattern_next = separator != ")";
+                continue;
+            }
+            _ => {}
+        }
+        while let Some(offset) = words[i..].iter().position(|word| !is_assignment(word)) {
+            i += offset;
+            let program = program_name(words[i]);
+            if program.is_empty() {
+                break;
+            }
+            let args = &words[i + 1..];
+            invocations.push(Invocation {
+                program: program.clone(),
+                args: args.join(" "),
+                mentioned: false,
+            });
+            if INTERPRETERS.contains(&program.as_str()) {
+                // check what the interpreter is given to run as well
+                let rest = args.iter().skip_while(|word| word.starts_with('-'));
+                invocations.extend(self::invocations(&rest.copied().collect::<Vec<_>>().join(" ")));
+                break;
+            }
+            if SCRIPT_INTERPRETERS.contains(&program.as_str()) {
+                // the script's own `;` and parentheses split it up along with
+                // the command, so the rest of the whole command is checked
+                let script = &command[ends[i]..];
+                invocations.extend(script_word.find_iter(script).map(|word| Invocation {
+                    program: word.as_str().to_string(),
+                    args: script.to_string(),
+                    mentioned: true,
+                }));
+                break;
+            }
+            if program == "find" {
+                // `;` already ended the segment, so each action runs to its end
+                // or to a `+`
+                for (j, _) in args.iter().enumerate().filter(|(_, word)| FIND_ACTIONS.contains(word)) {
+                    let action = args[j + 1..].iter().take_while(|word| **word != "+" && **word != "{}+");
+                    invocations.extend(self::invocations(&action.copied().collect::<Vec<_>>().join(" ")));
+                }
+                break;
+            }
+            if !WRAPPERS.contains(&program.as_str()) {
+                break;
+            }
+            match wrapped(&program, args) {
+                Some(offset) => i += 1 + offset,
+                None => break,
+            }
+        }
+    }
+    invocations
+}
+
+/// Where the program a wrapper runs is among the wrapper's arguments: after
+/// its own flags, the values of those that take one, and its operands
+fn wrapped(wrapper: &str, args: &[&str]) -> Option<usize> {
+    let options = WRAPPER_OPTIONS
+        .iter()
+        .find(|(name, _)| *name == wrapper)
+        .map(|(_, options)| *options)
+        .unwrap_or_default();
+    let operands = WRAPPER_OPERANDS
+        .iter()
+        .find(|(name, _)| *name == wrapper)
+        .map_or(0, |(_, operands)| *operands);
+    let mut i = 0;
+    while let Some(arg) = args.get(i) {
+        if *arg == "--" {
+            i += 1;
+            break;
+        }
+        if !arg.starts_with('-') {
+            break;
+        }
+        i += if options.contains(arg) { 2 } else { 1 };
+    }
+    i += operands;
+    (i < args.len()).then_some(i)
+}
+
+/// The name a word runs a program by: without quotes, escapes or a directory
+/// (so `\rm`, `'rm'` and `/bin/rm` are all `rm`)
+fn program_name(word: &str) -> String {
+    let word = word.replace(['"', '\'', '\\'], "");
+    let name = word.rsplit('/').next().unwrap_or_default();
+    if !name.contains(['$', '*', '?']) {
+        name.to_string()
+    } else {
+        // a name built by expansion is kept whole, to be denied as one that
+        // can't be checked
+        word
+    }
+}
+
+fn default_sandbox_command() -> String {
+    "bwrap --ro-bind / / --dev /dev --proc /proc --tmpfs /tmp --unshare-net --die-with-parent --".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(json: &str) -> Policy {
+        json5::from_str(json).unwrap()
+    }
+
+    fn denied(policy: &Policy, command: &str) -> bool {
+        matches!(policy.check(command), Verdict::Deny(_))
+    }
+
+    #[test]
+    fn deny_sees_through_wrappers() {
+        let policy = policy(r#"{ "deny": ["rm"] }"#);
+        assert!(denied(&policy, "sudo -u admin rm -rf /srv"));
+        assert!(denied(&policy, "ls | xargs -n 1 -P 4 rm"));
+        assert!(denied(&policy, "busybox rm -rf /srv"));
+        assert!(denied(&policy, r"find . -name '*.log' -exec rm {} \;"));
+        assert!(denied(&policy, "find . -type f -execdir rm -f {} +"));
+        assert!(!denied(&policy, "find . -name '*.log' -print"));
+    }
+
+    #[test]
+    fn deny_checks_scripts_word_by_word() {
+        let policy = policy(r#"{ "deny": ["rm"] }"#);
+        assert!(denied(&policy, r#"python3 -c 'import subprocess; subprocess.run(["rm", "-rf", "x"])'"#));
+        assert!(denied(&policy, "perl -e 'system qq{rm -rf x}'"));
+        assert!(denied(&policy, r#"awk 'BEGIN { system("rm x") }'"#));
+        assert!(!denied(&policy, "python3 -c 'print(1 + 1)'"));
+    }
+
+    #[test]
+    fn expanded_names_are_denied() {
+        let policy = policy(r#"{ "deny": ["rm"] }"#);
+        assert!(denied(&policy, "x=rm; $x -rf /srv"));
+        assert!(denied(&policy, "${RM:-rm} x"));
+        let allow = super::tests::policy(r#"{ "allow": ["ls"] }"#);
+        assert!(denied(&allow, "$(echo ls)"));
+        // nothing to check it against
+        assert!(matches!(super::tests::policy("{}").check("$x"), Verdict::Run));
+    }
+
+    #[test]
+    fn allow_list_skips_wrapper_options() {
+        let policy = policy(r#"{ "allow": ["sudo", "ls", "python3"] }"#);
+        assert!(matches!(policy.check("sudo -u admin ls /root"), Verdict::Run));
+        assert!(denied(&policy, "sudo -u admin cat /etc/shadow"));
+        // words of a script aren't programs the allow list has to cover
+        assert!(matches!(policy.check("python3 -c 'import this'"), Verdict::Run));
+    }
+
+    fn guarded() -> Policy {
+        policy(r#"{ "deny": ["rm"], "dry-run": [{ "program": "kubectl", "args": "\\b(delete|drain)\\b" }] }"#)
+    }
+
+    fn runs(policy: &Policy, command: &str) -> bool {
+        matches!(policy.check(command), Verdict::Run)
+    }
+
+    #[test]
+    fn loops_are_looked_into() {
+        let policy = guarded();
+        assert!(!runs(&policy, r#"for f in *.log; do rm "$f"; done"#));
+        assert!(!runs(&policy, "while true; do kubectl delete pod x; done"));
+        assert!(!runs(&policy, "until false; do rm x; done"));
+        // the words a loop goes over aren't programs
+        let allow = super::tests::policy(r#"{ "allow": ["ls", "rm"] }"#);
+        assert!(runs(&allow, "for f in *; do ls $f; done"));
+        assert!(runs(&allow, "for rm in a b; do ls; done"));
+    }
+
+    #[test]
+    fn conditionals_are_looked_into() {
+        let policy = guarded();
+        assert!(!runs(&policy, "if true; then rm -rf /srv; fi"));
+        assert!(!runs(&policy, "if false; then ls; elif true; then ls; else rm -rf /srv; fi"));
+        assert!(!runs(&policy, "! rm -rf /srv"));
+        assert!(runs(&policy, "if true; then ls; fi"));
+    }
+
+    #[test]
+    fn groups_are_looked_into() {
+        let policy = guarded();
+        assert!(!runs(&policy, "{ rm -rf /srv; }"));
+        assert!(!runs(&policy, "ls && { kubectl delete ns prod; }"));
+        assert!(!runs(&policy, "(rm -rf /srv)"));
+        assert!(runs(&policy, "{ ls; kubectl get pods; }"));
+    }
+
+    #[test]
+    fn case_patterns_are_skipped() {
+        let policy = guarded();
+        assert!(!runs(&policy, "case $x in a) rm -rf /srv;; esac"));
+        assert!(!runs(&policy, "case $x in\n  a|b) ls;;\n  *) kubectl drain node;;\nesac"));
+        // patterns are words to match, not programs
+        let allow = super::tests::policy(r#"{ "allow": ["ls"] }"#);
+        assert!(runs(&allow, "case $x in rm) ls;; (cat|sh) ls;; esac"));
+        assert!(denied(&allow, "case $x in a) rm x;; esac; cat y"));
+    }
+
+    #[test]
+    fn deny_sees_through_process_wrappers() {
+        let policy = policy(r#"{ "deny": ["rm"] }"#);
+        assert!(denied(&policy, "timeout 5 rm -rf /srv"));
+        assert!(denied(&policy, "timeout -s KILL -k 10 5m rm x"));
+        assert!(denied(&policy, "setsid rm x"));
+        assert!(denied(&policy, "stdbuf -oL rm x"));
+        assert!(denied(&policy, "stdbuf -o L rm x"));
+        assert!(denied(&policy, "ionice -c 3 rm x"));
+        assert!(denied(&policy, "chrt -f 10 rm x"));
+        assert!(denied(&policy, "taskset -c 0,1 rm x"));
+        assert!(denied(&policy, "nsenter -t 1 -m rm x"));
+        assert!(denied(&policy, "unbuffer rm x"));
+        assert!(!denied(&policy, "timeout 5 ls"));
+    }
+}
@@ -0,0 +1,138 @@
+use std::borrow::Cow;
+
+use anyhow::{Context, Result};
+use regex::{Captures, Regex};
+use serde_json::Value;
+
+use crate::config;
+
+const MASK: &str = "[REDACTED]";
+
+/// Secrets masked by default. Where a pattern has a group named `secret`,
+/// only that part is masked so the model still sees what it belonged to
+const PATTERNS: &[&str] = &[
+    // private keys, whole
+    r"-----BEGIN [A-Z0-9 ]*PRIVATE KEY( BLOCK)?-----[\s\S]*?-----END [A-Z0-9 ]*PRIVATE KEY( BLOCK)?-----",
+    // passwords in URLs
+    r"\b[a-zA-Z][a-zA-Z0-9+.-]*://[^\s:/@]*:(?P<secret>[^\s/@]+)@",
+    // Authorization headers
+    r"(?i)\b(?:bearer|basic|token)\s+(?P<secret>[A-Za-z0-9._~+/-]{12,}=*)",
+    // API keys of OpenAI, Anthropic, OpenRouter and the like
+    r"\bsk-[A-Za-z0-9_-]{20,}",
+    // GitHub and GitLab tokens
+    r"\b(?:ghp|gho|ghu|ghs|ghr)_[A-Za-z0-9]{30,}",
+    r"\bgithub_pat_[A-Za-z0-9_]{30,}",
+    r"\bglpat-[A-Za-z0-9_-]{20,}",
+    // AWS access key IDs
+    r"\b(?:AKIA|ASIA)[0-9A-Z]{16}\b",
+    // Slack tokens
+    r"\bxox[abposr]-[A-Za-z0-9-]{10,}",
+    // Google API keys
+    r"\bAIza[0-9A-Za-z_-]{35}",
+    // JWTs
+    r"\beyJ[A-Za-z0-9_-]{8,}\.eyJ[A-Za-z0-9_-]{8,}\.[A-Za-z0-9_-]{8,}",
+    // `password=...`, `"api_key": "..."`, `GITHUB_TOKEN=...`, `SECRET_KEY=...`
+    // and so on, the name ending in what it holds so `max_tokens=...` is left be
+    r#"(?i)[\w.-]*(?:token|secret|password|api[_-]?key)(?:[_.-](?:access[_.-])?key)?["']?\s*[:=]\s*["']?(?P<secret>[^\s"',;&]{4,})"#,
+];
+
+/// Masks secrets in text on its way to the model
+pub struct Redactor {
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    /// Built-in patterns, then the configured ones, then `secrets` (such as
+    /// the API keys in use) verbatim. Nothing is masked when redaction is off
+    pub fn new(redact: &config::Redact, secrets: &[&str]) -> Result<Self> {
+        if !redact.enabled {
+            return Ok(Self { patterns: Vec::new() });
+        }
+
+        let mut patterns = PATTERNS
+            .iter()
+            .map(|pattern| Regex::new(pattern).expect("built-in redaction patterns are valid"))
+            .collect::<Vec<_>>();
+        for pattern in &redact.patterns {
+            patterns.push(
+                Regex::new(pattern).with_context(|| format!("Invalid redact pattern: {}", pattern))?,
+            );
+        }
+        // short values would mask too much that isn't secret at all
+        for secret in secrets.iter().filter(|secret| secret.len() >= 8) {
+            patterns.push(Regex::new(&regex::escape(secret))?);
+        }
+        Ok(Self { patterns })
+    }
+
+    /// `text` with everything matching a pattern masked
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for pattern in &self.patterns {
+            if let Cow::Owned(redacted) = pattern.replace_all(&text, mask) {
+                text = Cow::Owned(redacted);
+            }
+        }
+        text
+    }
+
+    /// Redact the content of every message in a chat request body
+    pub fn redact_messages(&self, body: &mut Value) {
+        let Some(messages) = body.get_mut("messages").and_then(Value::as_array_mut) else {
+            return;
+        };
+        for message in messages {
+            if let Some(Value::String(content)) = message.get_mut("content")
+                && let Cow::Owned(redacted) = self.redact(content)
+            {
+                *content = redacted;
+            }
+        }
+    }
+}
+
+/// Replacement for a match: just its `secret` group if it has one, otherwise
+/// all of it
+fn mask(captures: &Captures) -> String {
+    let whole = captures.get(0).unwrap();
+    match captures.name("secret") {
+        Some(secret) => {
+            let text = whole.as_str();
+            let start = secret.start() - whole.start();
+            let end = secret.end() - whole.start();
+            format!("{}{}{}", &text[..start], MASK, &text[end..])
+        }
+        None => MASK.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor() -> Redactor {
+        Redactor::new(&config::Redact::default(), &[]).unwrap()
+    }
+
+    #[test]
+    fn named_secrets_are_masked() {
+        let redactor = redactor();
+        for (text, redacted) in [
+            ("DB_PASSWORD=hunter22", "DB_PASSWORD=[REDACTED]"),
+            ("GITHUB_TOKEN: abcd1234", "GITHUB_TOKEN: [REDACTED]"),
+            (r#""api_key": "abcd1234""#, r#""api_key": "[REDACTED]""#),
+            ("AWS_SECRET_ACCESS_KEY=abcd1234", "AWS_SECRET_ACCESS_KEY=[REDACTED]"),
+            ("SECRET_KEY='abcd1234'", "SECRET_KEY='[REDACTED]'"),
+        ] {
+            assert_eq!(redactor.redact(text), redacted);
+        }
+    }
+
+    #[test]
+    fn settings_that_only_look_secret_are_kept() {
+        let redactor = redactor();
+        for text in ["max_tokens=1000", "count=5", "PWD=/home/user", "tokenizer=bert", "keyboard=us"] {
+            assert_eq!(redactor.redact(text), text);
+        }
+    }
+}
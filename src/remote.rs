@@ -0,0 +1,116 @@
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result};
+
+use crate::config;
+
+/// Programs whose presence on a remote host is worth telling the model
+/// about, since they decide how a command would best be written
+const TOOLS: &[&str] = &[
+    "apt", "dnf", "yum", "pacman", "apk", "zypper", "brew", "systemctl", "service", "journalctl",
+    "docker", "podman", "kubectl", "git", "python3", "perl", "curl", "wget", "rg", "fd", "jq",
+    "gawk", "rsync", "sudo", "ip", "ss", "netstat",
+];
+
+/// Printed before the probe's output, so anything the remote login scripts
+/// print first can be skipped
+const MARKER: &str = "please-probe";
+
+/// What commands for a remote host need to be written for
+#[derive(Debug)]
+pub struct Environment {
+    /// From `uname -srm`
+    pub system: String,
+    /// From `/etc/os-release`, if it has one
+    pub distribution: Option<String>,
+    /// The login shell, which runs commands sent over ssh
+    pub shell: String,
+    /// Which of the common tools checked for are installed
+    pub tools: Vec<String>,
+}
+
+impl Environment {
+    /// The environment, for the model
+    pub fn describe(&self, host: &str) -> String {
+        let mut description = format!(
+            "The command will run over ssh on the remote host {}, not on this machine. It runs {}",
+            host, self.system
+        );
+        if let Some(distribution) = &self.distribution {
+            description.push_str(&format!(" ({})", distribution));
+        }
+        description.push_str(&format!(" and its shell is {}.", self.shell));
+
+        let missing = TOOLS
+            .iter()
+            .filter(|tool| !self.tools.iter().any(|installed| installed == *tool))
+            .copied()
+            .collect::<Vec<_>>();
+        if !self.tools.is_empty() {
+            description.push_str(&format!(" Installed there: {}.", self.tools.join(", ")));
+        }
+        if !missing.is_empty() {
+            description.push_str(&format!(" Not installed there: {}.", missing.join(", ")));
+        }
+        description
+    }
+}
+
+/// The ssh invocation that runs `command` on `host`, where the login shell
+/// runs it. `tty` asks for a terminal on the other end, for commands that
+/// are interactive
+pub fn command(ssh: &config::Ssh, host: &str, command: &str, tty: bool) -> Result<Command> {
+    // it would be taken as an option
+    if host.starts_with('-') {
+        anyhow::bail!("Invalid host: {}", host);
+    }
+    let mut process = Command::new(&ssh.program);
+    process.args(&ssh.args);
+    if tty {
+        process.arg("-t");
+    }
+    process.arg(host).arg(command);
+    Ok(process)
+}
+
+/// Find out what `host` runs, over ssh
+pub fn probe(ssh: &config::Ssh, host: &str) -> Result<Environment> {
+    // single quotes are left for wrapping it, so it runs in sh whatever the
+    // login shell is
+    let script = format!(
+        r#"echo {}; uname -srm; (. /etc/os-release && echo "$PRETTY_NAME") 2>/dev/null || echo; echo "$SHELL"; for tool in {}; do command -v "$tool" >/dev/null 2>&1 && printf "%s " "$tool"; done; echo"#,
+        MARKER,
+        TOOLS.join(" ")
+    );
+    let output = command(ssh, host, &format!("sh -c '{}'", script), false)?
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("Failed to run {}", ssh.program))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "Failed to reach {} ({}): {}",
+            host,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout
+        .lines()
+        .skip_while(|line| line.trim() != MARKER)
+        .skip(1)
+        .map(str::trim);
+    let (Some(system), Some(distribution), Some(shell), Some(tools)) =
+        (lines.next(), lines.next(), lines.next(), lines.next())
+    else {
+        anyhow::bail!("Unexpected output from {} while looking around it", host);
+    };
+    Ok(Environment {
+        system: system.to_string(),
+        distribution: (!distribution.is_empty()).then(|| distribution.to_string()),
+        // sshd sets $SHELL, but a login shell runs commands either way
+        shell: if shell.is_empty() { "sh" } else { shell }.to_string(),
+        tools: tools.split_whitespace().map(str::to_string).collect(),
+    })
+}
@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+
+const SNIPPETS_FILE: &str = r#"// please snippets; run one with `please run <name>`
+// {{placeholder}} and {{placeholder:default}} are asked for at run time, e.g.
+//     "big-files": { "command": "du -ah {{dir:.}} | sort -rh | head -n {{count:10}}" },
+{
+}
+"#;
+
+/// A saved command
+#[derive(Debug, Clone, Deserialize)]
+pub struct Snippet {
+    /// The command, with `{{name}}` placeholders
+    pub command: String,
+
+    /// The request the command was generated for, if any
+    #[serde(default)]
+    pub request: Option<String>,
+}
+
+/// A `{{name}}` or `{{name:default}}` in a snippet's command
+pub struct Placeholder {
+    /// The placeholder's name
+    pub name: String,
+    /// What it's filled with when nothing is typed
+    pub default: Option<String>,
+}
+
+/// Saved commands, kept in `$XDG_CONFIG_HOME/please/snippets.json5` so they
+/// can be edited by hand
+pub struct Snippets {
+    path: PathBuf,
+}
+
+impl Snippets {
+    /// The snippets in `$XDG_CONFIG_HOME/please/snippets.json5`
+    pub fn open() -> Result<Self> {
+        let xdg_dirs = xdg::BaseDirectories::with_prefix("please")
+            .context("Failed to initialize XDG directories")?;
+        Ok(Self {
+            path: xdg_dirs.get_config_home().join("snippets.json5"),
+        })
+    }
+
+    /// Every snippet, by name
+    pub fn all(&self) -> Result<BTreeMap<String, Snippet>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read snippets: {}", self.path.display()));
+            }
+        };
+        json5::from_str(&content)
+            .with_context(|| format!("Failed to parse snippets: {}", self.path.display()))
+    }
+
+    /// Add a snippet at the end of the file, leaving the rest of it (and any
+    /// comments) as it is
+    pub fn save(&self, name: &str, command: &str, request: Option<&str>) -> Result<()> {
+        if !is_valid_name(name) {
+            anyhow::bail!("Snippet names may only contain letters, digits, '-', '_' and '.'");
+        }
+        if self.all()?.contains_key(name) {
+            anyhow::bail!(
+                "There's already a snippet named {}; edit {} to change it",
+                name,
+                self.path.display()
+            );
+        }
+
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => SNIPPETS_FILE.to_string(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read snippets: {}", self.path.display()));
+            }
+        };
+        let end = closing_brace(&content)
+            .with_context(|| format!("No closing brace in {}", self.path.display()))?;
+
+        let quote = |text: &str| serde_json::to_string(text).unwrap_or_default();
+        let mut entry = format!("    {}: {{\n        \"command\": {},\n", quote(name), quote(command));
+        if let Some(request) = request {
+            entry.push_str(&format!("        \"request\": {},\n", quote(request)));
+        }
+        entry.push_str("    },\n");
+
+        // the entry before may not have a trailing comma
+        let before = content[..end].trim_end();
+        let separator = if before.ends_with('{') || before.ends_with(',') { "" } else { "," };
+        let updated = format!("{}{}\n{}{}", before, separator, entry, &content[end..]);
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).context("Failed to create config directory")?;
+        }
+        fs::write(&self.path, updated)
+            .with_context(|| format!("Failed to write snippets: {}", self.path.display()))
+    }
+}
+
+/// Where the top-level object in JSON5 `content` ends, past any braces in
+/// strings and comments
+fn closing_brace(content: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut chars = content.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' | '\'' => {
+                // to the matching quote, past escaped ones
+                while let Some((_, next)) = chars.next() {
+                    match next {
+                        '\\' => {
+                            chars.next();
+                        }
+                        next if next == c => break,
+                        _ => {}
+                    }
+                }
+            }
+            '/' if chars.next_if(|&(_, next)| next == '/').is_some() => {
+                for (_, next) in chars.by_ref() {
+                    if next == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.next_if(|&(_, next)| next == '*').is_some() => {
+                let mut star = false;
+                for (_, next) in chars.by_ref() {
+                    if star && next == '/' {
+                        break;
+                    }
+                    star = next == '*';
+                }
+            }
+            '{' => depth += 1,
+            '}' if depth == 1 => return Some(i),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Whether `name` can name a snippet: letters, digits, `-`, `_` and `.`
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || "-_.".contains(c))
+}
+
+impl Snippet {
+    /// Each distinct placeholder in the command, in order of appearance
+    pub fn placeholders(&self) -> Vec<Placeholder> {
+        let mut placeholders: Vec<Placeholder> = Vec::new();
+        for captures in placeholder_regex().captures_iter(&self.command) {
+            let name = captures[1].to_string();
+            if placeholders.iter().all(|placeholder| placeholder.name != name) {
+                placeholders.push(Placeholder {
+                    name,
+                    default: captures.get(2).map(|default| default.as_str().to_string()),
+                });
+            }
+        }
+        placeholders
+    }
+
+    /// The command with every placeholder replaced by its value
+    pub fn fill(&self, values: &BTreeMap<String, String>) -> String {
+        placeholder_regex()
+            .replace_all(&self.command, |captures: &regex::Captures| {
+                values.get(&captures[1]).cloned().unwrap_or_default()
+            })
+            .into_owned()
+    }
+}
+
+fn placeholder_regex() -> Regex {
+    Regex::new(r"\{\{\s*([\w-]+)\s*(?::([^}]*))?\}\}").unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_goes_inside_the_object_past_braces_in_comments() {
+        let path = std::env::temp_dir().join(format!("please-snippets-{}.json5", std::process::id()));
+        fs::write(
+            &path,
+            format!("{}\n// kept for {{ later }}\n", SNIPPETS_FILE.replace("{\n}", "{\n    \"a\": { command: \"echo '}'\" }\n}")),
+        )
+        .unwrap();
+        let snippets = Snippets { path: path.clone() };
+
+        snippets.save("b", "echo b", Some("say b")).unwrap();
+        let all = snippets.all().unwrap();
+        assert_eq!(all.keys().collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(all["a"].command, "echo '}'");
+        assert!(fs::read_to_string(&path).unwrap().ends_with("// kept for { later }\n"));
+        let _ = fs::remove_file(path);
+    }
+}
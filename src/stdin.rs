@@ -0,0 +1,180 @@
+use std::collections::VecDeque;
+use std::io::{self, IsTerminal, Read};
+use std::os::fd::{AsRawFd, RawFd};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+/// What was found on stdin
+pub enum Piped {
+    /// A terminal, or a pipe that closed without input
+    Nothing,
+    /// A pipe that nothing was written to in time; stdin left open by whatever
+    /// started `please` isn't waited on forever
+    Skipped,
+    /// Input piped in
+    Input(PipedInput),
+}
+
+/// Input piped into `please`, e.g. `cat build.log | please find the failing test`
+pub struct PipedInput {
+    pub content: String,
+    /// Bytes dropped from the middle to stay under the limit
+    pub truncated: usize,
+}
+
+/// Read stdin if it's a pipe or file rather than a terminal, keeping at most
+/// `limit` bytes split between the start and the end of the input; the end of
+/// a log is usually where the interesting part is. Input that hasn't started
+/// within `timeout` is skipped
+pub fn read_piped(limit: usize, timeout: Duration) -> Result<Piped> {
+    let stdin = io::stdin();
+    if stdin.is_terminal() {
+        return Ok(Piped::Nothing);
+    }
+    if !readable(stdin.as_raw_fd(), timeout).context("Failed to read piped input")? {
+        return Ok(Piped::Skipped);
+    }
+
+    let head_limit = limit / 2;
+    let tail_limit = limit - head_limit;
+    let mut head = Vec::with_capacity(head_limit);
+    let mut tail = VecDeque::with_capacity(tail_limit);
+    let mut total = 0;
+
+    // larger than stdin's own buffer, so reads go straight to the descriptor
+    let mut stdin = stdin.lock();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let read = match stdin.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).context("Failed to read piped input"),
+        };
+        total += read;
+
+        let mut chunk = &buf[..read];
+        let room = head_limit - head.len();
+        if room > 0 {
+            let (to_head, rest) = chunk.split_at(room.min(chunk.len()));
+            head.extend_from_slice(to_head);
+            chunk = rest;
+        }
+        for &byte in chunk {
+            if tail.len() == tail_limit {
+                tail.pop_front();
+            }
+            if tail_limit > 0 {
+                tail.push_back(byte);
+            }
+        }
+    }
+
+    if total == 0 {
+        return Ok(Piped::Nothing);
+    }
+    Ok(Piped::Input(join(&head, tail.make_contiguous(), total)))
+}
+
+/// Whether `fd` has input (or its end) within `timeout`
+fn readable(fd: RawFd, timeout: Duration) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    loop {
+        // SAFETY: pollfd is a single valid entry
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) };
+        match ready {
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            -1 => return Err(io::Error::last_os_error()),
+            ready => return Ok(ready > 0),
+        }
+    }
+}
+
+/// The start and end of `total` bytes of input, cut to whole characters where
+/// the middle was dropped
+fn join(head: &[u8], tail: &[u8], total: usize) -> PipedInput {
+    if head.len() + tail.len() == total {
+        // nothing dropped, so a character may run from one into the other
+        let content = String::from_utf8_lossy(&[head, tail].concat()).into_owned();
+        return PipedInput { content, truncated: 0 };
+    }
+
+    let head = without_partial_end(head);
+    let tail = without_partial_start(tail);
+    let truncated = total - head.len() - tail.len();
+    let mut content = String::from_utf8_lossy(head).into_owned();
+    content.push_str(&format!("\n[... {} bytes truncated ...]\n", truncated));
+    content.push_str(&String::from_utf8_lossy(tail));
+    PipedInput { content, truncated }
+}
+
+/// `bytes` without a UTF-8 character cut off at the end
+fn without_partial_end(bytes: &[u8]) -> &[u8] {
+    // the last character starts at most 3 bytes before the end
+    for back in 1..=bytes.len().min(4) {
+        let byte = bytes[bytes.len() - back];
+        if byte & 0xc0 == 0x80 {
+            continue;
+        }
+        let width = match byte {
+            0xf0.. => 4,
+            0xe0.. => 3,
+            0xc0.. => 2,
+            _ => 1,
+        };
+        return if width > back { &bytes[..bytes.len() - back] } else { bytes };
+    }
+    bytes
+}
+
+/// `bytes` without the rest of a UTF-8 character cut off at the start
+fn without_partial_start(bytes: &[u8]) -> &[u8] {
+    let continuation = bytes.iter().take(3).take_while(|&&byte| byte & 0xc0 == 0x80).count();
+    &bytes[continuation..]
+}
+
+impl PipedInput {
+    /// Append this input to a request as context for the model
+    pub fn attach_to(&self, request: &str) -> String {
+        let note = if self.truncated > 0 {
+            " (truncated in the middle)"
+        } else {
+            ""
+        };
+        format!(
+            "{}\n\nThe following was piped into `please` as context{}. \
+It will not be available to the command on stdin.\n```\n{}\n```",
+            request,
+            note,
+            self.content.trim_end()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whole_input_is_kept_across_the_join() {
+        let input = "héllo".as_bytes();
+        // the é split between head and tail
+        let piped = join(&input[..2], &input[2..], input.len());
+        assert_eq!(piped.content, "héllo");
+        assert_eq!(piped.truncated, 0);
+    }
+
+    #[test]
+    fn truncation_cuts_on_characters() {
+        let input = "aé—bcdef—é".as_bytes();
+        // both ends of the kept input land inside a character
+        let piped = join(&input[..4], &input[input.len() - 4..], input.len());
+        assert_eq!(piped.content, "aé\n[... 11 bytes truncated ...]\né");
+        assert_eq!(piped.truncated, 11);
+    }
+}
@@ -0,0 +1,389 @@
+use std::io::{self, Read};
+use std::os::fd::RawFd;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use termios::{tcsetattr, Termios, ECHO, ICANON, ISIG, TCSANOW};
+use unicode_width::UnicodeWidthStr;
+
+const BRACKETED_PASTE_ON: &[u8] = b"\x1b[?2004h";
+const BRACKETED_PASTE_OFF: &[u8] = b"\x1b[?2004l";
+
+/// Signals that would otherwise end the process with the terminal still raw
+const SIGNALS: &[libc::c_int] = &[libc::SIGINT, libc::SIGTERM, libc::SIGHUP, libc::SIGQUIT];
+
+/// Consecutive empty reads (of 0.1s each) after which a paste that never
+/// ends is given up on
+const PASTE_TIMEOUTS: usize = 20;
+/// Consecutive empty reads after which the rest of a character that takes
+/// several bytes is given up on
+const CHAR_TIMEOUTS: usize = 5;
+
+/// What's needed to put the terminal back from a signal handler, which can't
+/// reach the guard itself
+struct Saved {
+    fd: RawFd,
+    original: Termios,
+    handlers: Vec<(libc::c_int, libc::sigaction)>,
+}
+
+static SAVED: Mutex<Option<Saved>> = Mutex::new(None);
+
+/// Raw mode for reading single keys, with bracketed paste on. The terminal is
+/// put back how it was when this is dropped, whether by returning, an error
+/// or a panic, and also if the process is killed by a signal meanwhile.
+/// Ctrl-C and Ctrl-D arrive as keys rather than signals
+pub struct RawMode {
+    fd: RawFd,
+    original: Termios,
+}
+
+impl RawMode {
+    pub fn enable(fd: RawFd) -> Result<Self> {
+        let original = Termios::from_fd(fd).context("Failed to read terminal settings")?;
+        let mut raw = original;
+        raw.c_lflag &= !(ICANON | ECHO | ISIG);
+        raw.c_cc[termios::VMIN] = 0;   // don't wait
+        raw.c_cc[termios::VTIME] = 1;  // 0.1s timeout
+
+        // the handlers must be in place before the terminal needs restoring
+        let mut saved = SAVED.lock().unwrap_or_else(|e| e.into_inner());
+        *saved = Some(Saved {
+            fd,
+            original,
+            handlers: SIGNALS
+                .iter()
+                .filter_map(|&signal| Some((signal, install_handler(signal)?)))
+                .collect(),
+        });
+        drop(saved);
+
+        let guard = Self { fd, original };
+        tcsetattr(fd, TCSANOW, &raw).context("Failed to set terminal to raw mode")?;
+        write_fd(fd, BRACKETED_PASTE_ON);
+        Ok(guard)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        write_fd(self.fd, BRACKETED_PASTE_OFF);
+        let _ = tcsetattr(self.fd, TCSANOW, &self.original);
+
+        let mut saved = SAVED.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(saved) = saved.take() {
+            for (signal, handler) in &saved.handlers {
+                // SAFETY: puts back the action that was replaced in `enable`
+                unsafe { libc::sigaction(*signal, handler, std::ptr::null_mut()) };
+            }
+        }
+    }
+}
+
+/// Replace the action for `signal` with `restore_and_raise`, returning the
+/// one it replaced. Signals that were being ignored are left that way
+fn install_handler(signal: libc::c_int) -> Option<libc::sigaction> {
+    // SAFETY: sigaction is given valid pointers, and the handler only makes
+    // async-signal-safe calls
+    unsafe {
+        let mut previous: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(signal, std::ptr::null(), &mut previous) != 0
+            || previous.sa_sigaction == libc::SIG_IGN
+        {
+            return None;
+        }
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = restore_and_raise as *const () as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+            return None;
+        }
+        Some(previous)
+    }
+}
+
+/// Put the terminal back, then let the signal do whatever it would have done
+/// without us
+extern "C" fn restore_and_raise(signal: libc::c_int) {
+    // the lock is only held briefly outside of handlers; if it's held right
+    // now the terminal is about to be restored anyway
+    if let Ok(saved) = SAVED.try_lock()
+        && let Some(saved) = saved.as_ref()
+    {
+        write_fd(saved.fd, BRACKETED_PASTE_OFF);
+        let _ = tcsetattr(saved.fd, TCSANOW, &saved.original);
+        if let Some((_, handler)) = saved.handlers.iter().find(|(s, _)| *s == signal) {
+            // SAFETY: restores the previous action, as `Drop` would have
+            unsafe { libc::sigaction(signal, handler, std::ptr::null_mut()) };
+        }
+    }
+    // SAFETY: raise is async-signal-safe
+    unsafe { libc::raise(signal) };
+}
+
+fn write_fd(fd: RawFd, bytes: &[u8]) {
+    // SAFETY: writes from a valid buffer; a terminal opened read-only just
+    // doesn't get bracketed paste
+    unsafe { libc::write(fd, bytes.as_ptr().cast(), bytes.len()) };
+}
+
+/// A key, or pasted text, read in raw mode
+pub enum Key {
+    Enter,
+    Escape,
+    /// Ctrl-C or Ctrl-D
+    Interrupt,
+    CtrlE,
+    /// An arrow key, by the last byte of its sequence: `A` up, `B` down,
+    /// `C` right and `D` left
+    Arrow(u8),
+    /// A typed character (which may take several bytes) or a whole paste
+    Text(String),
+}
+
+/// Wait for the next key that means something, skipping other control keys
+/// and escape sequences
+pub fn read_key(input: &mut impl Read) -> Result<Key> {
+    loop {
+        if let Some(key) = next_key(input)? {
+            return Ok(key);
+        }
+    }
+}
+
+/// The next key if one that means something comes before the read times
+/// out, for polling between other work
+pub fn next_key(input: &mut impl Read) -> Result<Option<Key>> {
+    let Some(byte) = read_byte(input)? else {
+        return Ok(None);
+    };
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        0x03 | 0x04 => Key::Interrupt,
+        0x05 => Key::CtrlE,
+        0x1b => match read_byte(input)? {
+            // nothing followed in time, so it was the key itself
+            None => Key::Escape,
+            Some(b'[') => match read_sequence(input)? {
+                (_, Some(last @ b'A'..=b'D')) => Key::Arrow(last),
+                (params, Some(b'~')) if params == b"200" => Key::Text(read_paste(input)?),
+                _ => return Ok(None),
+            },
+            // arrows in application cursor mode
+            Some(b'O') => match read_byte(input)? {
+                Some(last @ b'A'..=b'D') => Key::Arrow(last),
+                _ => return Ok(None),
+            },
+            Some(_) => return Ok(None),
+        },
+        b'\t' => Key::Text("\t".to_string()),
+        byte if byte < 0x20 || byte == 0x7f => return Ok(None),
+        byte => Key::Text(read_char(byte, input)?),
+    };
+    Ok(Some(key))
+}
+
+/// One byte, or `None` if none came before the read timed out
+fn read_byte(input: &mut impl Read) -> Result<Option<u8>> {
+    let mut buf = [0u8; 1];
+    loop {
+        match input.read(&mut buf) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(buf[0])),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).context("Failed to read from the terminal"),
+        }
+    }
+}
+
+/// The parameters and final byte of a control sequence, after its `ESC [`
+fn read_sequence(input: &mut impl Read) -> Result<(Vec<u8>, Option<u8>)> {
+    let mut params = Vec::new();
+    while let Some(byte) = read_byte(input)? {
+        if (0x40..=0x7e).contains(&byte) {
+            return Ok((params, Some(byte)));
+        }
+        params.push(byte);
+    }
+    Ok((params, None))
+}
+
+/// Everything up to the end of a bracketed paste
+fn read_paste(input: &mut impl Read) -> Result<String> {
+    const END: &[u8] = b"\x1b[201~";
+    let mut pasted = Vec::new();
+    let mut timeouts = 0;
+    while !pasted.ends_with(END) && timeouts < PASTE_TIMEOUTS {
+        match read_byte(input)? {
+            Some(byte) => {
+                pasted.push(byte);
+                timeouts = 0;
+            }
+            None => timeouts += 1,
+        }
+    }
+    if pasted.ends_with(END) {
+        pasted.truncate(pasted.len() - END.len());
+    }
+    Ok(String::from_utf8_lossy(&pasted).into_owned())
+}
+
+/// The character a UTF-8 sequence starting with `first` encodes
+fn read_char(first: u8, input: &mut impl Read) -> Result<String> {
+    let length = match first.leading_ones() {
+        2 => 2,
+        3 => 3,
+        4 => 4,
+        _ => 1,
+    };
+    let mut bytes = vec![first];
+    let mut timeouts = 0;
+    while bytes.len() < length && timeouts < CHAR_TIMEOUTS {
+        match read_byte(input)? {
+            Some(byte) => bytes.push(byte),
+            None => timeouts += 1,
+        }
+    }
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// The width of the terminal, in columns
+pub fn columns(fd: RawFd) -> usize {
+    // SAFETY: TIOCGWINSZ fills in the winsize it's given
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } == 0 && size.ws_col > 0 {
+        return size.ws_col as usize;
+    }
+    std::env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .filter(|&columns| columns > 0)
+        .unwrap_or(80)
+}
+
+/// How many rows `text` takes up once printed, with long lines wrapped at
+/// `columns`. Colors and other escape sequences take up no room
+pub fn rows(text: &str, columns: usize) -> usize {
+    text.split('\n')
+        .map(|line| strip_escapes(line).width().div_ceil(columns.max(1)).max(1))
+        .sum()
+}
+
+/// Clear the last `rows` rows printed, leaving the cursor where the first of
+/// them began. The cursor must be at the start of the row after them
+pub fn clear_rows(rows: usize) {
+    if rows > 0 {
+        print!("\r\x1b[{}A\x1b[J", rows);
+    }
+}
+
+fn strip_escapes(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // skip to the end of the sequence, e.g. the `m` of a color
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() || c == '~' {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// Input that arrives in chunks, with an empty chunk for a read that
+    /// times out
+    struct Chunks(VecDeque<Vec<u8>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some(mut chunk) = self.0.pop_front() else {
+                return Ok(0);
+            };
+            let n = chunk.len().min(buf.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            if n < chunk.len() {
+                self.0.push_front(chunk.split_off(n));
+            }
+            Ok(n)
+        }
+    }
+
+    fn keys(mut input: impl Read) -> Vec<String> {
+        let mut keys = Vec::new();
+        for _ in 0..10 {
+            let key = match next_key(&mut input).unwrap() {
+                Some(Key::Enter) => "Enter".to_string(),
+                Some(Key::Escape) => "Escape".to_string(),
+                Some(Key::Interrupt) => "Interrupt".to_string(),
+                Some(Key::CtrlE) => "CtrlE".to_string(),
+                Some(Key::Arrow(arrow)) => format!("Arrow {}", arrow as char),
+                Some(Key::Text(text)) => format!("Text {:?}", text),
+                None => continue,
+            };
+            keys.push(key);
+        }
+        keys
+    }
+
+    #[test]
+    fn keys_and_arrows() {
+        assert_eq!(
+            keys(&b"\x1b[A\x1bOD\x1b[1;5C\x05\x03\r\x01x"[..]),
+            ["Arrow A", "Arrow D", "Arrow C", "CtrlE", "Interrupt", "Enter", "Text \"x\""]
+        );
+        // an escape with nothing after it in time is the key itself
+        assert_eq!(keys(&b"\x1b"[..]), ["Escape"]);
+        // other sequences are skipped
+        assert_eq!(keys(&b"\x1b[3~\x1b[15;2~y"[..]), ["Text \"y\""]);
+    }
+
+    #[test]
+    fn bracketed_paste() {
+        assert_eq!(
+            keys(&b"\x1b[200~ls -l\r\nrm \x1b[A\x1b[201~z"[..]),
+            ["Text \"ls -l\\r\\nrm \\u{1b}[A\"", "Text \"z\""]
+        );
+    }
+
+    #[test]
+    fn paste_without_end_marker() {
+        assert_eq!(keys(&b"\x1b[200~half a paste"[..]), ["Text \"half a paste\""]);
+    }
+
+    #[test]
+    fn split_utf8() {
+        let input = Chunks(VecDeque::from([vec![0xc3], vec![], vec![0xa9], vec![0xe2, 0x82], vec![0xac]]));
+        assert_eq!(keys(input), ["Text \"é\"", "Text \"€\""]);
+        // a character that never gets finished isn't waited on forever
+        assert_eq!(keys(&[0xf0, 0x9f][..]), ["Text \"\u{fffd}\""]);
+    }
+
+    #[test]
+    fn rows_wrap_and_ignore_colors() {
+        assert_eq!(rows("", 80), 1);
+        assert_eq!(rows("ls -l", 80), 1);
+        assert_eq!(rows(&"x".repeat(80), 80), 1);
+        assert_eq!(rows(&"x".repeat(81), 80), 2);
+        assert_eq!(rows("one\ntwo\n", 80), 3);
+        let colored = format!("\x1b[3;90m{}\x1b[0m", "x".repeat(80));
+        assert_eq!(rows(&colored, 80), 1);
+        // wide characters take two columns each
+        assert_eq!(rows("日本語", 4), 2);
+    }
+
+    #[test]
+    fn strips_escapes() {
+        assert_eq!(strip_escapes("\x1b[1;31mred\x1b[0m and \x1b[4mline\x1b[24m"), "red and line");
+    }
+}
@@ -0,0 +1,398 @@
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::config::ToolsConfig;
+use crate::policy::{Policy, Verdict};
+
+/// Most entries listed by `list_dir`
+const MAX_ENTRIES: usize = 200;
+/// Lines returned by `read_file_head` unless the model asks for fewer
+const DEFAULT_LINES: usize = 40;
+const MAX_LINES: usize = 200;
+/// Most bytes of file contents or program output handed back to the model
+const MAX_OUTPUT: usize = 8192;
+/// How long a program gets to finish before it's killed, and how long its
+/// output is read for
+const RUN_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long output is still read for after a program exits, for whatever it
+/// left in its pipes
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+/// The only arguments `run_readonly` will pass to a program
+const READONLY_FLAGS: &[&str] = &["--help", "--version"];
+
+/// Read-only tools the model may call to look around before answering.
+/// Paths are confined to the directory `please` was started in, and the only
+/// programs run are configured ones on `$PATH` that the system policy lets
+/// run, with `--help` or `--version` as their sole argument
+pub struct Toolbox {
+    root: PathBuf,
+    allow: Vec<String>,
+    programs: Vec<String>,
+    policy: Option<Policy>,
+}
+
+#[derive(Deserialize)]
+struct PathArgs {
+    #[serde(default)]
+    path: Option<String>,
+    #[serde(default)]
+    lines: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct ProgramArgs {
+    program: String,
+    #[serde(default)]
+    flag: Option<String>,
+}
+
+impl Toolbox {
+    /// Tools working in the current directory, limited as configured
+    pub fn new(tools: &ToolsConfig) -> Result<Self> {
+        let root = env::current_dir()
+            .and_then(|dir| dir.canonicalize())
+            .context("Failed to resolve the current directory")?;
+        Ok(Self {
+            root,
+            allow: tools.allow.clone(),
+            programs: tools.programs.clone(),
+            policy: Policy::load()?,
+        })
+    }
+
+    /// Tool definitions to send along with a chat request
+    pub fn definitions(&self) -> Vec<Value> {
+        let definitions = [
+            (
+                "list_dir",
+                "List the entries of a directory under the current directory; directories end in /",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string", "description": "Relative path, defaults to ." }
+                    }
+                }),
+            ),
+            (
+                "read_file_head",
+                "Read the first lines of a text file under the current directory",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string", "description": "Relative path of the file" },
+                        "lines": { "type": "integer", "description": "Number of lines, at most 200" }
+                    },
+                    "required": ["path"]
+                }),
+            ),
+            (
+                "which",
+                "Find whether a program is installed, and where",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "program": { "type": "string", "description": "Name of the program" }
+                    },
+                    "required": ["program"]
+                }),
+            ),
+            (
+                "run_readonly",
+                "Run an installed program with --help or --version and return its output. Only some programs may be run",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "program": { "type": "string", "description": "Name of the program" },
+                        "flag": { "type": "string", "enum": READONLY_FLAGS }
+                    },
+                    "required": ["program", "flag"]
+                }),
+            ),
+        ];
+
+        definitions
+            .into_iter()
+            .filter(|(name, _, _)| self.allows(name))
+            .map(|(name, description, parameters)| {
+                json!({
+                    "type": "function",
+                    "function": {
+                        "name": name,
+                        "description": description,
+                        "parameters": parameters,
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// Run a tool call from the model; failures are reported back to the
+    /// model as text rather than stopping the request
+    pub fn call(&self, name: &str, arguments: &str) -> String {
+        let result = if self.allows(name) {
+            self.dispatch(name, arguments)
+        } else {
+            Err(anyhow::anyhow!("Unknown or disallowed tool: {}", name))
+        };
+        result.unwrap_or_else(|e| format!("Error: {:#}", e))
+    }
+
+    fn allows(&self, name: &str) -> bool {
+        self.allow.iter().any(|allowed| allowed == name)
+    }
+
+    fn dispatch(&self, name: &str, arguments: &str) -> Result<String> {
+        // models sometimes send no arguments at all for tools that take none
+        let arguments = if arguments.trim().is_empty() { "{}" } else { arguments };
+        match name {
+            "list_dir" | "read_file_head" => {
+                let args: PathArgs = serde_json::from_str(arguments).context("Invalid arguments")?;
+                let path = self.resolve(args.path.as_deref().unwrap_or("."))?;
+                if name == "list_dir" {
+                    list_dir(&path)
+                } else {
+                    read_file_head(&path, args.lines.unwrap_or(DEFAULT_LINES).min(MAX_LINES))
+                }
+            }
+            "which" | "run_readonly" => {
+                let args: ProgramArgs = serde_json::from_str(arguments).context("Invalid arguments")?;
+                let program = which(&args.program)?;
+                if name == "which" {
+                    return Ok(program.display().to_string());
+                }
+                let flag = args.flag.unwrap_or_default();
+                if !READONLY_FLAGS.contains(&flag.as_str()) {
+                    anyhow::bail!("Flag must be one of {}", READONLY_FLAGS.join(", "));
+                }
+                if !self.programs.contains(&args.program) {
+                    anyhow::bail!("Only these programs may be run: {}", self.programs.join(", "));
+                }
+                if let Some(policy) = &self.policy
+                    && !matches!(policy.check(&format!("{} {}", args.program, flag)), Verdict::Run)
+                {
+                    anyhow::bail!("{} may not be run here", args.program);
+                }
+                run_readonly(&program, &flag)
+            }
+            _ => anyhow::bail!("Unknown tool: {}", name),
+        }
+    }
+
+    /// Resolve a path from the model, refusing anything outside the root
+    /// (including by way of `..` or symlinks)
+    fn resolve(&self, path: &str) -> Result<PathBuf> {
+        let resolved = self
+            .root
+            .join(path)
+            .canonicalize()
+            .with_context(|| format!("No such file or directory: {}", path))?;
+        if !resolved.starts_with(&self.root) {
+            anyhow::bail!("{} is outside the current directory", path);
+        }
+        Ok(resolved)
+    }
+}
+
+fn list_dir(path: &Path) -> Result<String> {
+    let mut entries = fs::read_dir(path)
+        .with_context(|| format!("Failed to list {}", path.display()))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let mut name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                name.push('/');
+            }
+            name
+        })
+        .collect::<Vec<_>>();
+    entries.sort();
+
+    let total = entries.len();
+    entries.truncate(MAX_ENTRIES);
+    if total > MAX_ENTRIES {
+        entries.push(format!("[... {} more entries ...]", total - MAX_ENTRIES));
+    }
+    Ok(entries.join("\n"))
+}
+
+fn read_file_head(path: &Path, lines: usize) -> Result<String> {
+    // reading a FIFO or a device could block forever
+    let metadata = fs::metadata(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    if !metadata.is_file() {
+        anyhow::bail!("{} is not a regular file", path.display());
+    }
+    let mut bytes = Vec::new();
+    fs::File::open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?
+        .take(MAX_OUTPUT as u64)
+        .read_to_end(&mut bytes)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    if bytes.contains(&0) {
+        anyhow::bail!("{} looks like a binary file", path.display());
+    }
+
+    let content = String::from_utf8_lossy(&bytes);
+    Ok(content.lines().take(lines).collect::<Vec<_>>().join("\n"))
+}
+
+/// Find a program by name on `$PATH`; paths are not accepted, so only
+/// installed programs can be found
+pub fn which(program: &str) -> Result<PathBuf> {
+    if program.is_empty() || program.contains('/') {
+        anyhow::bail!("Expected the name of a program, not a path: {}", program);
+    }
+    let path = env::var_os("PATH").unwrap_or_default();
+    env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| {
+            candidate
+                .metadata()
+                .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        })
+        .with_context(|| format!("{} not found on $PATH", program))
+}
+
+fn run_readonly(program: &Path, flag: &str) -> Result<String> {
+    let mut command = Command::new(program);
+    command.arg(flag);
+    let (mut output, status) = capture(command, MAX_OUTPUT)?;
+    match status {
+        None => output.push_str(&format!("\n[killed after {}s]", RUN_TIMEOUT.as_secs())),
+        Some(status) if !status.success() => output.push_str(&format!("\n[{}]", status)),
+        Some(_) => {}
+    }
+    Ok(output)
+}
+
+/// Run a command without a terminal or pagers, returning its combined output
+/// (cut off after `limit` bytes) and exit status; the status is `None` when it
+/// had to be killed for taking too long. The command runs in a process group
+/// of its own, so anything it started is killed along with it
+pub fn capture(mut command: Command, limit: usize) -> Result<(String, Option<ExitStatus>)> {
+    let mut child = command
+        .env("PAGER", "cat")
+        .env("MANPAGER", "cat")
+        .env("GIT_PAGER", "cat")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .with_context(|| format!("Failed to run {}", command.get_program().to_string_lossy()))?;
+
+    // read both pipes as the program writes, so a chatty program can't block
+    let readers = [
+        child.stdout.take().map(|out| Box::new(out) as Box<dyn Read + Send>),
+        child.stderr.take().map(|err| Box::new(err) as Box<dyn Read + Send>),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|mut pipe| {
+        let bytes = Arc::new(Mutex::new(Vec::new()));
+        let read = bytes.clone();
+        let reader = thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            while let Ok(n) = pipe.read(&mut buf)
+                && n > 0
+            {
+                read.lock().unwrap().extend_from_slice(&buf[..n]);
+            }
+        });
+        (reader, bytes)
+    })
+    .collect::<Vec<_>>();
+
+    let deadline = Instant::now() + RUN_TIMEOUT;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if Instant::now() >= deadline {
+            // SAFETY: signals the process group the child leads
+            unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+            let _ = child.wait();
+            break None;
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+
+    // something that escaped the process group can hold the pipes open
+    // indefinitely, so stop reading rather than wait for it
+    let drained = Instant::now() + DRAIN_TIMEOUT;
+    while readers.iter().any(|(reader, _)| !reader.is_finished()) && Instant::now() < drained {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mut output = Vec::new();
+    for (_, bytes) in &readers {
+        output.extend_from_slice(&bytes.lock().unwrap());
+    }
+    let mut output = String::from_utf8_lossy(&output).into_owned();
+    if output.len() > limit {
+        let mut end = limit;
+        while !output.is_char_boundary(end) {
+            end -= 1;
+        }
+        output.truncate(end);
+        output.push_str("\n[... truncated ...]");
+    }
+    Ok((output, status))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::*;
+
+    #[test]
+    fn capture_does_not_wait_for_background_children() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "sleep 30 & echo started"]);
+        let started = Instant::now();
+        let (output, status) = capture(command, MAX_OUTPUT).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+        assert_eq!(output.trim(), "started");
+        assert!(status.unwrap().success());
+    }
+
+    #[test]
+    fn read_file_head_refuses_fifos() {
+        let dir = env::temp_dir().join(format!("please-tools-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let fifo = dir.join("fifo");
+        let _ = fs::remove_file(&fifo);
+        let path = CString::new(fifo.as_os_str().as_bytes()).unwrap();
+        // SAFETY: mkfifo is given a valid path
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+
+        let error = read_file_head(&fifo, DEFAULT_LINES).unwrap_err();
+        assert!(error.to_string().contains("not a regular file"), "{:#}", error);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn run_readonly_only_runs_configured_programs() {
+        let toolbox = Toolbox::new(&ToolsConfig {
+            programs: vec!["sh".to_string()],
+            ..ToolsConfig::default()
+        })
+        .unwrap();
+        let refused = toolbox.call("run_readonly", r#"{"program": "ls", "flag": "--version"}"#);
+        assert!(refused.starts_with("Error: Only these programs may be run: sh"), "{}", refused);
+        let refused = toolbox.call("run_readonly", r#"{"program": "sh", "flag": "-c"}"#);
+        assert!(refused.starts_with("Error: Flag must be one of"), "{}", refused);
+    }
+}
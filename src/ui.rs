@@ -1,149 +1,356 @@
-use std::io::{stdin, Read, Write};
-use std::os::fd::{AsRawFd};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{stdin, IsTerminal, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use rustyline::config::Behavior;
 use rustyline::error::ReadlineError;
 use rustyline::{DefaultEditor, Event, EventHandler, KeyCode, KeyEvent, Modifiers};
 use colored::Colorize;
-use termios::{tcsetattr, Termios, ECHO, ICANON, TCSANOW};
 
-use crate::config::Config;
+use please::api::Notice;
+use please::config::Config;
+use please::snippets;
+
+use crate::terminal::{self, Key, RawMode};
+
+const SPINNER: &[char] = &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
 
 pub enum UserAction {
     RunCommand(String),
     ProvideFeedback(String),
     EditCommand(String),
+    /// Keep the command as a snippet under this name
+    Save(String),
+    /// Only offered for steps of a plan
+    Skip,
+    /// Backed out of editing or refining the command, to be shown it again
+    Back,
     Quit,
 }
 
 pub struct UI {
     editor: DefaultEditor,
     config: Config,
+    /// The model being asked, for the spinner; it changes on fallback
+    model: Arc<Mutex<String>>,
+    /// Keys typed while waiting on the model, to be read before the terminal
+    typed: Mutex<Vec<u8>>,
 }
 
 impl UI {
     pub fn new(config: Config) -> Result<Self> {
-        let mut editor = DefaultEditor::new()?;
+        // when stdin is piped input, talk to the terminal directly
+        let behavior = if stdin().is_terminal() {
+            Behavior::Stdio
+        } else {
+            Behavior::PreferTerm
+        };
+        // without a timeout, Esc waits for the rest of an escape sequence
+        // rather than being a key of its own
+        let editor_config = rustyline::Config::builder()
+            .behavior(behavior)
+            .keyseq_timeout(Some(100))
+            .build();
+        let mut editor = DefaultEditor::with_config(editor_config)?;
         editor.bind_sequence(Event::KeySeq(vec![KeyEvent(KeyCode::Esc, Modifiers::empty())]), EventHandler::Simple(rustyline::Cmd::Interrupt));
-        Ok(Self { editor, config })
+        Ok(Self {
+            editor,
+            config,
+            model: Arc::default(),
+            typed: Mutex::default(),
+        })
+    }
+
+    /// A hook for the API client's notices, which keeps them from running into
+    /// the spinner and keeps the spinner's model up to date
+    pub fn notice_hook(&self) -> impl Fn(&Notice) + Send + Sync + 'static {
+        let model = self.model.clone();
+        move |notice| {
+            if let Notice::FallingBack { next, .. } = notice {
+                *model.lock().unwrap() = next.clone();
+            }
+            // the spinner is drawn again below on its next tick
+            if std::io::stdout().is_terminal() {
+                print!("\r\x1b[2K");
+                let _ = std::io::stdout().flush();
+            }
+            match notice {
+                // the request itself, so not in italics
+                Notice::Outbound { .. } => eprintln!("{}", notice.to_string().bright_black()),
+                _ => eprintln!("{}", notice.to_string().italic().bright_black()),
+            }
+        }
     }
 
     /// Display a command and get user action
     /// Returns:
     /// - UserAction::RunCommand if user presses Enter (run as-is)
     /// - UserAction::ProvideFeedback if user types feedback
-    /// - UserAction::EditCommand if user edits the command and presses Enter
-    /// - UserAction::Quit if user presses Ctrl-C or Ctrl-D
-    pub fn display_command_and_get_action(&mut self, command: &str) -> Result<UserAction> {
+    /// - UserAction::EditCommand if user edits the command and presses Enter,
+    ///   or presses Ctrl-E and edits it in $EDITOR
+    /// - UserAction::Save if user types `/save <name>`
+    /// - UserAction::Back if user presses Esc or Ctrl-C while editing or refining
+    /// - UserAction::Quit if user presses Esc, Ctrl-C or Ctrl-D
+    ///
+    /// When a fallback chain is configured, `model` is shown so it's clear which
+    /// model produced the command. The `flagged` parts of the command (flags
+    /// the programs don't document) are underlined.
+    pub fn display_command_and_get_action(&mut self, command: &str, model: Option<&str>, flagged: &[Range<usize>]) -> Result<UserAction> {
+        let mut display = String::new();
+        let mut end = 0;
+        for span in flagged {
+            display.push_str(&command[end..span.start]);
+            display.push_str(&command[span.clone()].red().underline().to_string());
+            end = span.end;
+        }
+        display.push_str(&command[end..]);
+
+        let shown = match model {
+            Some(model) if self.config.model.len() > 1 && !self.config.quiet => {
+                let model = format!("({})", model);
+                println!("{} {}", display, model.bright_black());
+                format!("{} {}", command, model)
+            }
+            _ => {
+                println!("{}", display);
+                command.to_string()
+            }
+        };
+        self.get_action(command, &shown, false)
+    }
+
+    /// Display one step of a plan and get user action; like
+    /// `display_command_and_get_action`, but Tab returns UserAction::Skip
+    pub fn display_step_and_get_action(&mut self, number: usize, total: usize, description: &str, command: &str) -> Result<UserAction> {
+        println!("{} {}", format!("[{}/{}]", number, total).bright_black(), description.bold());
         println!("{}", command);
+        self.get_action(command, command, true)
+    }
 
-        if !self.config.quiet {
-            let message = format!("{} {} {}",
+    /// `shown` is the command as it was printed, for working out how many
+    /// rows to clear when it's edited
+    fn get_action(&mut self, command: &str, shown: &str, allow_skip: bool) -> Result<UserAction> {
+        let help = if self.config.quiet {
+            String::new()
+        } else {
+            let skip = if allow_skip {
+                format!(" {} {}", "Tab".bright_black(), "to skip,".bright_black().italic())
+            } else {
+                String::new()
+            };
+            format!("{} {} {}{} {} {} {}",
                 "Press".bright_black().italic(),
                 "Enter".bright_black(),
-                "to run, type feedback to refine, or press arrow keys to edit the command.".bright_black().italic()
-            );
-            println!("{}", message);
+                "to run,".bright_black().italic(),
+                skip,
+                "type feedback to refine (or /save <name>), or press arrow keys (or".bright_black().italic(),
+                "Ctrl-E".bright_black(),
+                "for $EDITOR) to edit the command.".bright_black().italic()
+            )
         };
+        if !self.config.quiet {
+            println!("{}", help);
+        }
         std::io::stdout().flush()?;
 
-        let stdin = stdin();
-        let fd = stdin.as_raw_fd();
-        
-        // save original terminal settings
-        let old_tio = Termios::from_fd(fd)?;
-        let mut new_tio = old_tio;
-        
-        // raw mode: disable canonical and echo
-        new_tio.c_lflag &= !(ICANON | ECHO);
-        new_tio.c_cc[termios::VMIN] = 0;   // don't wait
-        new_tio.c_cc[termios::VTIME] = 1;  // 0.1s timeout
-        
-        tcsetattr(fd, TCSANOW, &new_tio)?;
-        
-        let mut stdin = stdin.lock();
-        let mut buf = [0u8; 1];
-        
-        loop {
-            if stdin.read(&mut buf)? == 1 {
-                if buf[0] == 27 {  // ESC
-                    // try to read next byte with timeout
-                    let mut next = [0u8; 1];
-                    if stdin.read(&mut next)? == 0 {
-                        // timeout! actual escape key
-                        return Ok(UserAction::Quit);
-                    } else if next[0] == b'[' {
-                        // escape sequence, read third byte
-                        let mut third = [0u8; 1];
-                        let len = stdin.read(&mut third)?;
-                        if len == 0 {
-                            continue;
-                        }
-                        match third[0] {
-                            // up, right, down, left
-                            c @ (b'A' | b'B' | b'C' | b'D') => {
-                                // transfer power over to readline
-                                tcsetattr(fd, TCSANOW, &old_tio)?;
-                                if self.config.quiet {
-                                    print!("\r\033[2K"); // Clear current line
-                                } else {
-                                    // Cursor is at end of help message line
-                                    print!("\r\x1b[2K\x1b[A\r\x1b[2K"); // clear help line
-                                    print!("\r\x1b[2K\x1b[A\r\x1b[2K"); // clear command line
-                                }
-                                std::io::stdout().flush()?;
-
-                                let initial = if c == b'D' {
-                                    // left arrow, put cursor at end
-                                    let (left, right) = command.split_at(command.len() - 1);
-                                    (left, right)
-                                } else {
-                                    (command, "")
-                                };
-
-                                return self.get_from_readline(initial).map(UserAction::EditCommand);
-                            },
-                            _ => {
-                                continue;
-                            },
-                        }
-                    }
+        // keys typed while waiting on the model come first
+        let typed = std::mem::take(&mut *self.typed.lock().unwrap());
+        let (key, typed, columns) = with_raw_terminal(|input, fd| {
+            let mut typed = typed.as_slice();
+            let key = terminal::read_key(&mut Read::chain(&mut typed, input))?;
+            Ok((key, typed.to_vec(), terminal::columns(fd)))
+        })?;
+
+        // rows to clear to edit the command in place, cursor being just
+        // below the help
+        let help_rows = if self.config.quiet { 0 } else { terminal::rows(&help, columns) };
+        let command_rows = terminal::rows(shown, columns);
+
+        match key {
+            Key::Enter => Ok(UserAction::RunCommand(command.to_string())),
+            Key::Text(text) if allow_skip && text == "\t" => Ok(UserAction::Skip),
+            Key::Escape | Key::Interrupt => Ok(UserAction::Quit),
+            Key::Arrow(arrow) => {
+                // transfer power over to readline
+                terminal::clear_rows(help_rows + command_rows);
+                std::io::stdout().flush()?;
+
+                let initial = if arrow == b'D' {
+                    // left arrow, put cursor before the last character
+                    let last = command.char_indices().last().map_or(0, |(i, _)| i);
+                    command.split_at(last)
                 } else {
-                    let input_char = buf[0] as char;
-                    if input_char == '\n' || input_char == '\r' {
-                        // Enter pressed, run command
-                        tcsetattr(fd, TCSANOW, &old_tio)?;
-                        return Ok(UserAction::RunCommand(command.to_string()));
+                    (command, "")
+                };
+                Ok(self.get_from_readline(initial)?.map_or(UserAction::Back, UserAction::EditCommand))
+            }
+            Key::CtrlE => {
+                // for edits too long or too many lines for readline
+                terminal::clear_rows(help_rows + command_rows);
+                std::io::stdout().flush()?;
+
+                // either way the command is shown again, unchanged
+                let edited = match self.edit_in_editor(command, "sh") {
+                    Ok(edited) => edited,
+                    Err(e) => {
+                        self.show_error(&format!("{:#}", e));
+                        return Ok(UserAction::Back);
                     }
+                };
+                let edited = edited.trim();
+                if edited.is_empty() {
+                    self.show_message("Empty command, keeping the one before");
+                    return Ok(UserAction::Back);
+                }
+                println!("{}", edited);
+                Ok(UserAction::EditCommand(edited.to_string()))
+            }
+            Key::Text(text) => {
+                // typed or pasted the start of some feedback; move to input
+                // area and transfer power over to readline
+                terminal::clear_rows(help_rows);
+                if !self.config.quiet {
+                    self.show_message("Refine:");
+                }
+                // along with the rest of what was typed ahead, up to a
+                // control key
+                let rest = String::from_utf8_lossy(&typed);
+                let text = text.replace(['\r', '\n'], " ")
+                    + &rest.chars().take_while(|c| !c.is_control()).collect::<String>();
+                let Some(feedback) = self.get_from_readline((&text, ""))? else {
+                    return Ok(UserAction::Back);
+                };
+                Ok(match save_name(&feedback) {
+                    Some(name) => UserAction::Save(name.to_string()),
+                    None => UserAction::ProvideFeedback(feedback),
+                })
+            }
+        }
+    }
 
-                    // we got a different character; move to input area and provide feedback
-                    // transfer power over to readline
-                    tcsetattr(fd, TCSANOW, &old_tio)?;
-                    if !self.config.quiet {
-                        print!("\r\x1b[2K\x1b[A\r\x1b[2K"); // clear help line
-                        self.show_message("Refine:");
+    /// Wait for a request to the model, with a spinner showing `label`, the
+    /// time taken so far and the model asked (just `label` when stdout isn't
+    /// a terminal; nothing when quiet). Esc or Ctrl-C cancel the request,
+    /// returning `None`
+    pub async fn wait_for<F: Future>(&self, label: &str, request: F) -> Result<Option<F::Output>> {
+        let spinning = !self.config.quiet && std::io::stdout().is_terminal();
+        if !self.config.quiet && !spinning {
+            self.show_message(label);
+        }
+        // each request starts over at the top of the chain
+        let first = self.config.model.first().map_or("", |model| model.name.as_str());
+        *self.model.lock().unwrap() = first.to_string();
 
-                    }
-                    let input = format!("{}", input_char);
-                    return self.get_from_readline((input.as_str(), "")).map(UserAction::ProvideFeedback);
+        let stop = Arc::new(AtomicBool::new(false));
+        let (cancel, mut cancelled) = tokio::sync::oneshot::channel();
+        let watcher = {
+            let stop = stop.clone();
+            // keys can't be read without blocking, so they're read on a thread
+            // of their own; a terminal that can't be read just can't cancel
+            tokio::task::spawn_blocking(move || watch_for_cancel(&stop, cancel).unwrap_or_default())
+        };
+
+        let started = Instant::now();
+        let mut ticks = tokio::time::interval(Duration::from_millis(100));
+        let mut frames = SPINNER.iter().cycle();
+        tokio::pin!(request);
+        let output = loop {
+            tokio::select! {
+                output = &mut request => break Some(output),
+                Ok(()) = &mut cancelled => break None,
+                _ = ticks.tick(), if spinning => {
+                    let status = format!(
+                        "{} {:.1}s {} · Esc to cancel",
+                        label,
+                        started.elapsed().as_secs_f64(),
+                        self.model.lock().unwrap()
+                    );
+                    print!("\r\x1b[2K{} {}", frames.next().unwrap(), status.italic().bright_black());
+                    // not worth giving up on the request over
+                    let _ = std::io::stdout().flush();
                 }
             }
+        };
+
+        // the terminal has to be back to normal before anything else is shown
+        stop.store(true, Ordering::Relaxed);
+        let typed = watcher.await?;
+        // what was typed before cancelling goes with it
+        if output.is_some() {
+            self.typed.lock().unwrap().extend(typed);
+        }
+        if spinning {
+            print!("\r\x1b[2K");
+            std::io::stdout().flush()?;
         }
+        if output.is_none() {
+            self.show_message("Cancelled");
+        }
+        Ok(output)
     }
 
-    pub fn get_from_readline_with_prompt(&mut self, prompt: impl std::fmt::Display, initial: (&str, &str)) -> Result<String> {
+    /// Let the user edit `text` in `$EDITOR`, by way of a temporary file
+    /// ending in `extension` (for syntax highlighting), and return what they
+    /// saved
+    pub fn edit_in_editor(&self, text: &str, extension: &str) -> Result<String> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or_default();
+        let path = env::temp_dir().join(format!("please-{}-{}.{}", std::process::id(), nanos, extension));
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .and_then(|mut file| writeln!(file, "{}", text))
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+
+        let opened = self.open_in_editor(&path);
+        let edited = fs::read_to_string(&path);
+        let _ = fs::remove_file(&path);
+
+        opened?;
+        edited.with_context(|| format!("Failed to read {}", path.display()))
+    }
+
+    /// Open a file in `$EDITOR` (which may have arguments of its own) and
+    /// wait for it to close; an editor that fails is an error
+    pub fn open_in_editor(&self, path: &Path) -> Result<()> {
+        let editor = env::var("EDITOR").context("EDITOR environment variable not set")?;
+        let mut words = editor.split_whitespace();
+        let program = words.next().context("EDITOR environment variable is empty")?;
+
+        let mut command = Command::new(program);
+        command.args(words).arg(path);
+        // the editor needs the terminal even when stdin is piped input
+        if !stdin().is_terminal() {
+            command.stdin(File::open("/dev/tty").context("stdin is not a terminal and /dev/tty could not be opened")?);
+        }
+        let status = command.status().with_context(|| format!("Failed to run {}", program))?;
+        if !status.success() {
+            anyhow::bail!("{} exited with {}", program, status);
+        }
+        Ok(())
+    }
+
+    /// A line of input, or `None` if the user backs out with Esc, Ctrl-C or
+    /// Ctrl-D
+    pub fn get_from_readline_with_prompt(&mut self, prompt: impl std::fmt::Display, initial: (&str, &str)) -> Result<Option<String>> {
         match self.editor.readline_with_initial(&prompt.to_string(), initial) {
-            Ok(string) => Ok(string),
-            Err(ReadlineError::Interrupted) => {
-                std::process::exit(1);
-            },
-            Err(e) => {
-                Err(e.into())
-            }
+            Ok(string) => Ok(Some(string)),
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => Ok(None),
+            Err(e) => Err(e.into()),
         }
     }
 
-    pub fn get_from_readline(&mut self, initial: (&str, &str)) -> Result<String> {
+    pub fn get_from_readline(&mut self, initial: (&str, &str)) -> Result<Option<String>> {
         self.get_from_readline_with_prompt("", initial)
     }
 
@@ -152,13 +359,76 @@ impl UI {
         eprintln!("{}", error);
     }
 
+    pub fn show_warning(&self, message: &str) {
+        eprintln!("{}", message.yellow());
+    }
+
     pub fn show_message(&self, message: &str) {
         let message = message.italic().bright_black();
         println!("{}", message);
     }
 
+    /// Ask a question; backing out with Esc or Ctrl-C answers it with nothing
     pub fn show_prompt(&mut self, prompt: impl std::fmt::Display) -> Result<String> {
         let input = self.get_from_readline_with_prompt(prompt, ("", ""))?;
-        Ok(input)
+        Ok(input.unwrap_or_default())
+    }
+}
+
+/// The name in feedback of the form `/save <name>`; without the slash, it's
+/// feedback like any other ("save output to a file")
+fn save_name(feedback: &str) -> Option<&str> {
+    let name = feedback.trim().strip_prefix("/save ")?.trim();
+    snippets::is_valid_name(name).then_some(name)
+}
+
+/// Run `read` with the terminal in raw mode, given where to read keys from
+/// and the terminal's descriptor. Keys are read from stdin, or from the
+/// terminal itself when stdin has been used up by piped input
+fn with_raw_terminal<T>(read: impl FnOnce(&mut dyn Read, RawFd) -> Result<T>) -> Result<T> {
+    // opened before the guard so it's still open when the guard restores it
+    let tty = if stdin().is_terminal() {
+        None
+    } else {
+        let tty = OpenOptions::new().read(true).write(true).open("/dev/tty");
+        Some(tty.context("stdin is not a terminal and /dev/tty could not be opened")?)
+    };
+    let fd = tty.as_ref().map_or_else(|| stdin().as_raw_fd(), |tty| tty.as_raw_fd());
+
+    let _raw = RawMode::enable(fd)?;
+    match tty.as_ref() {
+        Some(mut tty) => read(&mut tty, fd),
+        None => read(&mut stdin().lock(), fd),
+    }
+}
+
+/// Read keys until Esc or Ctrl-C, which send on `cancel`, or until `stop`,
+/// returning the bytes of any other keys so they can be read again
+fn watch_for_cancel(stop: &AtomicBool, cancel: tokio::sync::oneshot::Sender<()>) -> Result<Vec<u8>> {
+    with_raw_terminal(|input, _| {
+        let mut input = Recording { input, bytes: Vec::new() };
+        let mut typed = Vec::new();
+        while !stop.load(Ordering::Relaxed) {
+            if let Some(Key::Escape | Key::Interrupt) = terminal::next_key(&mut input)? {
+                let _ = cancel.send(());
+                break;
+            }
+            typed.append(&mut input.bytes);
+        }
+        Ok(typed)
+    })
+}
+
+/// Keeps a copy of everything read through it
+struct Recording<'a> {
+    input: &'a mut dyn Read,
+    bytes: Vec<u8>,
+}
+
+impl Read for Recording<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.input.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..n]);
+        Ok(n)
     }
 }
@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Token usage as reported by the endpoint
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Usage {
+    /// Tokens sent
+    #[serde(default)]
+    pub prompt_tokens: u64,
+
+    /// Tokens generated
+    #[serde(default)]
+    pub completion_tokens: u64,
+
+    /// Cost in credits (USD on OpenRouter), when the endpoint reports it
+    #[serde(default)]
+    pub cost: Option<f64>,
+}
+
+impl Usage {
+    /// Add `other` to this, cost included if either has one
+    pub fn add(&mut self, other: &Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.cost = match (self.cost, other.cost) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or_default() + b.unwrap_or_default()),
+        };
+    }
+
+    /// Tokens sent and generated
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+/// One line of the usage ledger
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Record {
+    /// When the request was made
+    pub timestamp: DateTime<Utc>,
+    /// The model that answered
+    pub model: String,
+    /// What it used
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+/// Append-only record of every request's usage, kept as JSONL in
+/// `$XDG_DATA_HOME/please/usage.jsonl`
+pub struct Ledger {
+    path: PathBuf,
+}
+
+/// Usage summed over some period for one model
+#[derive(Debug, Default)]
+pub struct Total {
+    /// Requests made
+    pub requests: u64,
+    /// What they used
+    pub usage: Usage,
+}
+
+impl Ledger {
+    /// The ledger in `$XDG_DATA_HOME/please/usage.jsonl`
+    pub fn open() -> Result<Self> {
+        let xdg_dirs = xdg::BaseDirectories::with_prefix("please")
+            .context("Failed to initialize XDG directories")?;
+        Ok(Self {
+            path: xdg_dirs.get_data_home().join("usage.jsonl"),
+        })
+    }
+
+    /// Append a request's usage
+    pub fn record(&self, model: &str, usage: &Usage) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).context("Failed to create data directory")?;
+        }
+        let record = Record {
+            timestamp: Utc::now(),
+            model: model.to_string(),
+            usage: *usage,
+        };
+        let mut line = serde_json::to_string(&record).context("Failed to serialize usage")?;
+        line.push('\n');
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .with_context(|| format!("Failed to write usage ledger: {}", self.path.display()))
+    }
+
+    /// Every record in the ledger; lines that can't be parsed are skipped
+    pub fn records(&self) -> Result<Vec<Record>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read usage ledger: {}", self.path.display()));
+            }
+        };
+
+        Ok(content
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    /// Totals keyed by (local period, model), where the period is the
+    /// timestamp formatted with `format` (e.g. "%Y-%m" for months)
+    pub fn totals(&self, format: &str) -> Result<BTreeMap<(String, String), Total>> {
+        let mut totals: BTreeMap<(String, String), Total> = BTreeMap::new();
+        for record in self.records()? {
+            let period = record.timestamp.with_timezone(&Local).format(format).to_string();
+            let total = totals.entry((period, record.model)).or_default();
+            total.requests += 1;
+            total.usage.add(&record.usage);
+        }
+        Ok(totals)
+    }
+
+    /// Cost of every request made so far in the current local month
+    pub fn month_cost(&self) -> Result<f64> {
+        self.cost_in(&Local::now().format("%Y-%m").to_string())
+    }
+
+    /// Fail if this month's requests have used up `budget`
+    pub fn check_budget(&self, budget: f64) -> Result<()> {
+        let spent = self.month_cost()?;
+        if spent >= budget {
+            anyhow::bail!(
+                "Monthly budget of ${:.2} reached (${:.4} spent this month). See `please stats`",
+                budget,
+                spent
+            );
+        }
+        Ok(())
+    }
+
+    /// Cost of the requests made in a local month, formatted as "%Y-%m"
+    fn cost_in(&self, month: &str) -> Result<f64> {
+        Ok(self
+            .totals("%Y-%m")?
+            .into_iter()
+            .filter(|((period, _), _)| period == month)
+            .filter_map(|(_, total)| total.usage.cost)
+            .sum())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn ledger(name: &str) -> Ledger {
+        let path = std::env::temp_dir().join(format!("please-usage-{}-{}.jsonl", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        Ledger { path }
+    }
+
+    fn usage(prompt_tokens: u64, completion_tokens: u64, cost: Option<f64>) -> Usage {
+        Usage { prompt_tokens, completion_tokens, cost }
+    }
+
+    /// Write a record as if made at a local time
+    fn record_at(ledger: &Ledger, (year, month, day, hour, min): (i32, u32, u32, u32, u32), usage: Usage) {
+        let record = Record {
+            timestamp: Local.with_ymd_and_hms(year, month, day, hour, min, 0).unwrap().with_timezone(&Utc),
+            model: "m".to_string(),
+            usage,
+        };
+        let line = serde_json::to_string(&record).unwrap() + "\n";
+        let mut file = OpenOptions::new().create(true).append(true).open(&ledger.path).unwrap();
+        file.write_all(line.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn totals_sum_by_period_and_model() {
+        let ledger = ledger("totals");
+        ledger.record("a", &usage(10, 5, Some(0.01))).unwrap();
+        ledger.record("a", &usage(20, 10, None)).unwrap();
+        ledger.record("b", &usage(1, 1, None)).unwrap();
+        OpenOptions::new().append(true).open(&ledger.path).unwrap().write_all(b"not json\n").unwrap();
+
+        let totals = ledger.totals("all").unwrap();
+        let a = &totals[&("all".to_string(), "a".to_string())];
+        assert_eq!((a.requests, a.usage.total_tokens(), a.usage.cost), (2, 45, Some(0.01)));
+        let b = &totals[&("all".to_string(), "b".to_string())];
+        assert_eq!((b.requests, b.usage.cost), (1, None));
+        let _ = fs::remove_file(&ledger.path);
+    }
+
+    #[test]
+    fn month_cost_stops_at_the_month_boundary() {
+        let ledger = ledger("boundary");
+        record_at(&ledger, (2026, 1, 31, 23, 59), usage(1, 1, Some(0.5)));
+        record_at(&ledger, (2026, 2, 1, 0, 1), usage(1, 1, Some(0.25)));
+        record_at(&ledger, (2026, 2, 28, 23, 59), usage(1, 1, Some(0.125)));
+        record_at(&ledger, (2026, 3, 1, 0, 0), usage(1, 1, Some(1.0)));
+        assert_eq!(ledger.cost_in("2026-01").unwrap(), 0.5);
+        assert_eq!(ledger.cost_in("2026-02").unwrap(), 0.375);
+        assert_eq!(ledger.cost_in("2026-04").unwrap(), 0.0);
+        let _ = fs::remove_file(&ledger.path);
+    }
+
+    #[test]
+    fn budget_blocks_once_spent() {
+        let ledger = ledger("budget");
+        ledger.check_budget(0.1).unwrap();
+        ledger.record("m", &usage(1, 1, Some(0.06))).unwrap();
+        ledger.check_budget(0.1).unwrap();
+        ledger.record("m", &usage(1, 1, Some(0.06))).unwrap();
+        let error = ledger.check_budget(0.1).unwrap_err().to_string();
+        assert!(error.starts_with("Monthly budget of $0.10 reached"), "{}", error);
+        let _ = fs::remove_file(&ledger.path);
+    }
+}
@@ -0,0 +1,129 @@
+//! `remote` against a fake ssh: a script that stands in for `ssh` and either
+//! prints canned output or runs the command it's given locally
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use please::config::Ssh;
+use please::remote;
+
+/// A directory of its own for each test, since they run in parallel
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("please-remote-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// An ssh whose program is a script with the given body, which logs the
+/// arguments it was run with to `args` next to it
+fn fake_ssh(dir: &Path, body: &str) -> Ssh {
+    let program = dir.join("ssh");
+    let log = dir.join("args");
+    fs::write(
+        &program,
+        format!("#!/bin/sh\nprintf '%s\\n' \"$@\" > '{}'\n{}\n", log.display(), body),
+    )
+    .unwrap();
+    fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+    Ssh {
+        host: None,
+        program: program.display().to_string(),
+        args: Vec::new(),
+    }
+}
+
+fn logged_args(dir: &Path) -> Vec<String> {
+    fs::read_to_string(dir.join("args"))
+        .unwrap_or_default()
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn probe_skips_login_banner() {
+    let dir = scratch("banner");
+    let ssh = fake_ssh(
+        &dir,
+        "cat <<'EOF'\nWelcome to web1!\nLast login: Mon Oct 12 from 10.0.0.1\nplease-probe\nLinux 6.1.0 x86_64\n\
+Debian GNU/Linux 12 (bookworm)\n/bin/bash\ngit curl jq \nEOF",
+    );
+
+    let environment = remote::probe(&ssh, "deploy@web1").unwrap();
+    assert_eq!(environment.system, "Linux 6.1.0 x86_64");
+    assert_eq!(environment.distribution.as_deref(), Some("Debian GNU/Linux 12 (bookworm)"));
+    assert_eq!(environment.shell, "/bin/bash");
+    assert_eq!(environment.tools, ["git", "curl", "jq"]);
+
+    let description = environment.describe("deploy@web1");
+    assert!(description.contains("Installed there: git, curl, jq."), "{}", description);
+    assert!(description.contains("Not installed there: apt,"), "{}", description);
+}
+
+#[test]
+fn probe_falls_back_without_os_release_or_shell() {
+    let dir = scratch("fallback");
+    let ssh = fake_ssh(&dir, "printf 'please-probe\\nFreeBSD 14.0 amd64\\n\\n\\n\\n'");
+
+    let environment = remote::probe(&ssh, "bsd").unwrap();
+    assert_eq!(environment.system, "FreeBSD 14.0 amd64");
+    assert_eq!(environment.distribution, None);
+    assert_eq!(environment.shell, "sh");
+    assert!(environment.tools.is_empty());
+}
+
+#[test]
+fn probe_runs_its_script_in_sh() {
+    let dir = scratch("script");
+    // run the command the way a login shell would, here
+    let ssh = fake_ssh(&dir, "shift $(($# - 1)); SHELL=/bin/fakesh exec sh -c \"$1\"");
+
+    let environment = remote::probe(&ssh, "localhost").unwrap();
+    assert!(!environment.system.is_empty());
+    assert_eq!(environment.shell, "/bin/fakesh");
+    assert!(environment.tools.iter().all(|tool| !tool.is_empty()));
+}
+
+#[test]
+fn probe_without_marker_is_an_error() {
+    let dir = scratch("nomarker");
+    let ssh = fake_ssh(&dir, "echo 'This account is restricted'");
+
+    let error = remote::probe(&ssh, "jail").unwrap_err();
+    assert!(error.to_string().contains("Unexpected output from jail"), "{:#}", error);
+}
+
+#[test]
+fn probe_reports_ssh_failure() {
+    let dir = scratch("failure");
+    let ssh = fake_ssh(&dir, "echo 'ssh: connect to host down: Connection refused' >&2; exit 255");
+
+    let error = remote::probe(&ssh, "down").unwrap_err();
+    assert!(error.to_string().contains("Connection refused"), "{:#}", error);
+}
+
+#[test]
+fn terminal_is_only_asked_for_when_interactive() {
+    let dir = scratch("tty");
+    let mut ssh = fake_ssh(&dir, "");
+    ssh.args = vec!["-p".to_string(), "2222".to_string()];
+
+    remote::command(&ssh, "web1", "top", true).unwrap().status().unwrap();
+    assert_eq!(logged_args(&dir), ["-p", "2222", "-t", "web1", "top"]);
+
+    remote::command(&ssh, "web1", "ls -l", false).unwrap().status().unwrap();
+    assert_eq!(logged_args(&dir), ["-p", "2222", "web1", "ls -l"]);
+}
+
+#[test]
+fn host_that_looks_like_an_option_is_rejected() {
+    let dir = scratch("option");
+    let ssh = fake_ssh(&dir, "");
+
+    assert!(remote::command(&ssh, "-oProxyCommand=touch /tmp/x", "ls", false).is_err());
+    assert!(remote::probe(&ssh, "-oProxyCommand=touch /tmp/x").is_err());
+    // ssh was never run
+    assert!(!dir.join("args").exists());
+}